@@ -7,7 +7,8 @@ let
     preferLocalBuild = true;
     src = cfg.configFile;
   } ''
-    ${cfg.package}/bin/ifdyndnsd --test $src
+    # Secrets and the machine id are not available in the sandbox
+    ${cfg.package}/bin/ifdyndnsd --test --offline $src
     cp $src $out
   '';
 
@@ -59,14 +60,28 @@ in {
     };
     users.groups.${cfg.group} = { };
 
+    environment.etc."ifdyndnsd/config.toml".source = configFileChecked;
+
     systemd.services.ifdyndnsd = {
       wantedBy = [ "multi-user.target" ];
+      # Config changes are applied with SIGHUP
+      reloadTriggers = [ configFileChecked ];
       environment.RUST_LOG = "ifdyndnsd=${cfg.logLevel}";
       serviceConfig = {
-        Type = "simple";
-        ExecStart = "${cfg.package}/bin/ifdyndnsd ${configFileChecked}";
+        # Ready once the addresses are known, with a summary of the
+        # records in `systemctl status`
+        Type = "notify";
+        # Pinged from the main loop between updates, as long as the
+        # netlink listener is alive. No pings are sent while a record
+        # is updated, including its retries with further secrets.
+        WatchdogSec = "2min";
+        ExecStart = "${cfg.package}/bin/ifdyndnsd /etc/ifdyndnsd/config.toml";
+        ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
         User = cfg.user;
         Group = cfg.group;
+        StateDirectory = "ifdyndnsd";
+        # For the control socket of `ifdyndnsd ctl`
+        RuntimeDirectory = "ifdyndnsd";
         Restart = "always";
         RestartSec = "1s";
       };
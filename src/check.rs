@@ -0,0 +1,89 @@
+//! Check that the servers are reachable and authorize the keys for
+//! all configured names, without changing any records
+
+use crate::retry::ErrorClass;
+use crate::{config, dns, AddressFamily, Error};
+use hickory_client::proto::rr::record_type::RecordType;
+use std::collections::HashMap;
+
+/// For every name, including neighbors, send a signed SOA query and
+/// an update that only deletes the address `0.0.0.0` or `::`, which
+/// is never published, and print the outcome per name. The update
+/// changes nothing but succeeds if the key is authorized for the name.
+///
+/// Returns whether all checks have passed.
+///
+/// # Errors
+///
+/// Will return `Err` if the configuration cannot be loaded or is
+/// invalid.
+pub async fn check(config_file: &str) -> Result<bool, Error> {
+    let config = config::load(config_file)?;
+    let tasks = config.tasks();
+    let defaults = config.defaults.unwrap_or_default();
+
+    let mut passed = true;
+    let mut servers = HashMap::new();
+    for (name, key) in &config.keys {
+        match dns::Server::new(key.server, key).await {
+            Ok(server) => {
+                servers.insert(name.as_str(), server);
+            }
+            Err(e) => {
+                println!("key {name}: cannot set up: {e}");
+                passed = false;
+            }
+        }
+    }
+
+    let (mut succeeded, mut failed) = (0, 0);
+    for (af, task) in &tasks {
+        let Some(server) = servers.get_mut(task.key.as_str()) else {
+            continue;
+        };
+        let key = &config.keys[&task.key];
+        let zone = task
+            .zone
+            .as_deref()
+            .or(key.default_zone.as_deref())
+            .or(defaults.zone.as_deref());
+        let record_type = match af {
+            AddressFamily::IPv4 => RecordType::A,
+            AddressFamily::IPv6 => RecordType::AAAA,
+        };
+        let names = task
+            .name
+            .iter()
+            .chain(task.neighbors.iter().flat_map(HashMap::keys));
+        for name in names {
+            let status = match server.query_soa(name, zone).await {
+                Err(e @ (dns::Error::Name { .. } | dns::Error::NotInZone { .. })) => {
+                    Err(e.to_string())
+                }
+                Err(e) if e.class() == ErrorClass::Network => Err(format!("unreachable: {e}")),
+                Err(e) if e.class() == ErrorClass::NotAuth => {
+                    Err(format!("reachable, TSIG failed: {e}"))
+                }
+                Err(e) => Err(format!("reachable, SOA query failed: {e}")),
+                Ok(()) => match server.check_update(name, zone, record_type).await {
+                    Ok(()) => Ok("reachable, TSIG OK, authorized".to_string()),
+                    Err(e) => Err(format!("reachable, TSIG OK, not authorized: {e}")),
+                },
+            };
+            let status = match status {
+                Ok(status) => {
+                    succeeded += 1;
+                    status
+                }
+                Err(status) => {
+                    failed += 1;
+                    status
+                }
+            };
+            println!("{name} {record_type} (key {}): {status}", task.key);
+        }
+    }
+
+    println!("{succeeded} names passed, {failed} failed");
+    Ok(passed && failed == 0)
+}
@@ -1,63 +1,536 @@
+use crate::secret::Secret;
+use crate::{keyfile, retry, template, AddressFamily};
 use base64::engine::general_purpose;
 use base64::Engine;
+use cidr::IpCidr;
+use hickory_client::proto::dnssec::rdata::tsig::TsigAlgorithm;
+use hickory_client::proto::rr::Name;
+use log::warn;
 use serde_derive::Deserialize;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufReader, Read};
-use std::net::{IpAddr, Ipv6Addr};
+use std::io::Read;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::ops::Range;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, ExitStatus, Stdio};
 use std::result::Result;
+use std::str::FromStr;
+use toml::de::{DeTable, DeValue};
+use toml::Spanned;
 
-#[derive(Debug, Deserialize)]
+/// Standard value according to RFC 2845, Sec. 6
+pub const DEFAULT_FUDGE: u16 = 300;
+
+/// Shortest `verify-interval`, in seconds, so that servers are not
+/// flooded with queries
+pub const MIN_VERIFY_INTERVAL: u64 = 60;
+
+/// A setting that takes a single value or a list
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum OneOrMore<T> {
+    One(T),
+    More(Vec<T>),
+}
+
+impl<T> OneOrMore<T> {
+    #[must_use]
+    pub fn as_slice(&self) -> &[T] {
+        match self {
+            OneOrMore::One(value) => std::slice::from_ref(value),
+            OneOrMore::More(values) => values,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct TsigKey {
     pub server: IpAddr,
-    pub name: String,
-    pub alg: String,
-    pub secret: Option<String>,
+    /// Optional with `key-file`, where it selects one of several keys
+    pub name: Option<String>,
+    /// Optional with `key-file`
+    pub alg: Option<String>,
+    /// BIND key file, e.g. from `ddns-confgen` or `tsig-keygen`
+    #[serde(rename = "key-file")]
+    pub key_file: Option<String>,
+    /// Each secret setting takes a list of secrets to rotate keys.
+    /// Secrets are tried in order until the server accepts one.
+    pub secret: Option<OneOrMore<Secret>>,
     #[serde(rename = "secret-base64")]
-    pub secret_base64: Option<String>,
+    pub secret_base64: Option<OneOrMore<Secret>>,
     #[serde(rename = "secret-file")]
-    pub secret_file: Option<String>,
+    pub secret_file: Option<OneOrMore<String>>,
     #[serde(rename = "secret-file-base64")]
-    pub secret_file_base64: Option<String>,
+    pub secret_file_base64: Option<OneOrMore<String>>,
+    /// Name of an environment variable
+    #[serde(rename = "secret-env")]
+    pub secret_env: Option<OneOrMore<String>>,
+    #[serde(rename = "secret-env-base64")]
+    pub secret_env_base64: Option<OneOrMore<String>>,
+    /// Name of a systemd credential in `$CREDENTIALS_DIRECTORY`
+    #[serde(rename = "secret-credential")]
+    pub secret_credential: Option<OneOrMore<String>>,
+    #[serde(rename = "secret-credential-base64")]
+    pub secret_credential_base64: Option<OneOrMore<String>>,
+    /// Shell command that prints the secret
+    #[serde(rename = "secret-command")]
+    pub secret_command: Option<OneOrMore<String>>,
+    #[serde(rename = "secret-command-base64")]
+    pub secret_command_base64: Option<OneOrMore<String>>,
+    /// Seconds that the clocks of client and server may differ
+    pub fudge: Option<u16>,
+    /// Sign with the server's time after `BADTIME`
+    #[serde(rename = "correct-skew")]
+    pub correct_skew: Option<bool>,
+    /// For records of this key without `zone`
+    #[serde(rename = "default-zone")]
+    pub default_zone: Option<String>,
+    #[serde(rename = "default-ttl")]
+    pub default_ttl: Option<u32>,
+    /// Up to one scope per address family
+    #[serde(rename = "default-scope")]
+    pub default_scope: Option<OneOrMore<String>>,
+}
+
+/// Name, algorithm and secrets of a key, from the config or a key file
+#[derive(Debug, PartialEq)]
+pub struct Credentials {
+    pub name: String,
+    pub alg: String,
+    /// At least one, in the order they should be tried
+    pub secrets: Vec<Secret>,
 }
 
 impl TsigKey {
+    /// A key for `server` without any other settings
     #[must_use]
-    /// # Panics
+    pub fn new(server: IpAddr) -> Self {
+        TsigKey {
+            server,
+            name: None,
+            alg: None,
+            key_file: None,
+            secret: None,
+            secret_base64: None,
+            secret_file: None,
+            secret_file_base64: None,
+            secret_env: None,
+            secret_env_base64: None,
+            secret_credential: None,
+            secret_credential_base64: None,
+            secret_command: None,
+            secret_command_base64: None,
+            fudge: None,
+            correct_skew: None,
+            default_zone: None,
+            default_ttl: None,
+            default_scope: None,
+        }
+    }
+
+    /// # Errors
+    ///
+    /// Will return `Err` if
     ///
+    /// - Neither `key-file` nor `name` and `alg` are configured.
+    /// - `key-file` cannot be read or parsed, or doesn't contain the
+    ///   key `name`, or `name` isn't set and it contains several keys.
+    /// - `alg` is set and differs from the algorithm in `key-file`.
+    /// - A secret cannot be read, see `get_secrets()`.
+    ///
+    pub fn credentials(&self) -> Result<Credentials, KeyError> {
+        let Some(key_file) = &self.key_file else {
+            let (Some(name), Some(alg)) = (&self.name, &self.alg) else {
+                return Err(KeyError::MissingNameOrAlg);
+            };
+            return Ok(Credentials {
+                name: name.clone(),
+                alg: alg.clone(),
+                secrets: self.read_secrets()?,
+            });
+        };
+        let names = self.secret_names();
+        if !names.is_empty() {
+            let mut conflicting = vec!["key-file"];
+            conflicting.extend(names);
+            return Err(KeyError::Ambiguous(conflicting));
+        }
+
+        let key = self.read_key_file(key_file)?;
+        if let Some(alg) = &self.alg {
+            if !alg.eq_ignore_ascii_case(&key.algorithm) {
+                return Err(KeyError::AlgorithmMismatch {
+                    alg: alg.clone(),
+                    key_file_alg: key.algorithm,
+                });
+            }
+        }
+        let secret = decode_base64("key-file", key.secret.expose())?;
+        Ok(Credentials {
+            name: key.name,
+            alg: key.algorithm,
+            secrets: vec![secret],
+        })
+    }
+
+    /// # Errors
+    ///
+    /// Will return `Err` if
+    ///
+    /// - Not exactly one of the secret parameters or `key-file` is configured.
+    /// - A list of secrets is empty.
     /// - `secret_base64` could not be decoded from base64.
     /// - File where `secret_file` or `secret_file_base64` points to does not exist or the user does not have permission to read it.
-    /// - Contents of file where `secret_file_base64` could not be decoded from base64.
+    /// - The environment variable of `secret-env` or `secret-env-base64` is not set.
+    /// - The credential of `secret-credential` or `secret-credential-base64`
+    ///   cannot be read, e.g. because `$CREDENTIALS_DIRECTORY` is not set.
+    /// - The command of `secret-command` or `secret-command-base64` cannot
+    ///   be run or fails.
+    /// - The value of any of the `*-base64` settings could not be decoded from base64.
+    /// - The key cannot be read from `key-file`, see `credentials()`.
     ///
-    pub fn get_secret(&self) -> Vec<u8> {
-        match (&self.secret, &self.secret_base64, &self.secret_file, &self.secret_file_base64) {
-            (Some(secret), None, None,None) => secret.bytes().collect::<Vec<u8>>(),
-            (None, Some(secret_base64), None, None) => general_purpose::STANDARD.decode(secret_base64).unwrap(),
-            (None, None, Some(secret_file), None) => {
-                let file = BufReader::new(File::open(secret_file).map_err(|e| format!("Failed to open the specified secret-file: {e}")).unwrap());
-                file.bytes().map(Result::unwrap).collect()
-            },
-            (None, None, None, Some(secret_file_base64)) => {
-                let mut file = File::open(secret_file_base64).map_err(|e| format!("Failed to open the specified secret-file-base64: {e}")).unwrap();
-                let mut buf = Vec::new();
-                file.read_to_end(&mut buf).unwrap();
-                general_purpose::STANDARD.decode(buf).unwrap()
-            },
-            (None, None, None, None) => panic!(
-                "Neither secret nor secret-base64 nor secret-file nor secret-file-base64 configured for key {}. Configure one of the secret parameters.",
-                self.name
+    pub fn get_secrets(&self) -> Result<Vec<Secret>, KeyError> {
+        if self.key_file.is_some() {
+            return self.credentials().map(|credentials| credentials.secrets);
+        }
+        self.read_secrets()
+    }
+
+    fn read_secrets(&self) -> Result<Vec<Secret>, KeyError> {
+        self.secret_source()?
+            .iter()
+            .map(SecretSetting::read)
+            .collect()
+    }
+
+    fn read_key_file(&self, key_file: &str) -> Result<keyfile::Key, KeyError> {
+        let mut keys = keyfile::load(key_file).map_err(|source| KeyError::KeyFile {
+            path: key_file.to_string(),
+            source,
+        })?;
+        match &self.name {
+            Some(name) => keys
+                .into_iter()
+                .find(|key| keyfile::same_name(&key.name, name))
+                .ok_or_else(|| KeyError::NoSuchKey {
+                    path: key_file.to_string(),
+                    name: name.clone(),
+                }),
+            None if keys.len() == 1 => Ok(keys.remove(0)),
+            None => Err(KeyError::NameRequired {
+                path: key_file.to_string(),
+                count: keys.len(),
+            }),
+        }
+    }
+
+    /// All secrets that are configured, one entry per value
+    fn secret_settings(&self) -> Vec<SecretSetting<'_>> {
+        let inline: fn(&Option<OneOrMore<Secret>>) -> Vec<&[u8]> = |secret| {
+            secret
+                .iter()
+                .flat_map(OneOrMore::as_slice)
+                .map(Secret::expose)
+                .collect()
+        };
+        let external: fn(&Option<OneOrMore<String>>) -> Vec<&[u8]> = |value| {
+            value
+                .iter()
+                .flat_map(OneOrMore::as_slice)
+                .map(String::as_bytes)
+                .collect()
+        };
+        [
+            ("secret", inline(&self.secret), Source::Inline),
+            ("secret-base64", inline(&self.secret_base64), Source::Inline),
+            ("secret-file", external(&self.secret_file), Source::File),
+            (
+                "secret-file-base64",
+                external(&self.secret_file_base64),
+                Source::File,
+            ),
+            ("secret-env", external(&self.secret_env), Source::Env),
+            (
+                "secret-env-base64",
+                external(&self.secret_env_base64),
+                Source::Env,
+            ),
+            (
+                "secret-credential",
+                external(&self.secret_credential),
+                Source::Credential,
+            ),
+            (
+                "secret-credential-base64",
+                external(&self.secret_credential_base64),
+                Source::Credential,
             ),
-            (_, _, _, _) => panic!(
-                "More than one of the parameters secret, secret-base64 or secret-file configured for key {}.
-                Configure exactly one of the secret parameters.",
-                self.name
+            (
+                "secret-command",
+                external(&self.secret_command),
+                Source::Command,
             ),
+            (
+                "secret-command-base64",
+                external(&self.secret_command_base64),
+                Source::Command,
+            ),
+        ]
+        .iter()
+        .flat_map(|(name, values, source)| {
+            values.iter().map(move |value| SecretSetting {
+                name,
+                value,
+                source: *source,
+                base64: name.ends_with("-base64"),
+            })
+        })
+        .collect()
+    }
+
+    /// Files containing the secret, with their setting
+    fn secret_files(&self) -> Vec<(&'static str, &str)> {
+        let key_file = self.key_file.iter().map(|path| ("key-file", path));
+        let secret_files = [
+            ("secret-file", &self.secret_file),
+            ("secret-file-base64", &self.secret_file_base64),
+        ];
+        let secret_files = secret_files.iter().flat_map(|(setting, paths)| {
+            paths
+                .iter()
+                .flat_map(OneOrMore::as_slice)
+                .map(move |path| (*setting, path))
+        });
+        key_file
+            .chain(secret_files)
+            .map(|(setting, path)| (setting, path.as_str()))
+            .collect()
+    }
+
+    /// Names of the secret settings that are configured
+    fn secret_names(&self) -> Vec<&'static str> {
+        let mut names = self
+            .secret_settings()
+            .iter()
+            .map(|setting| setting.name)
+            .collect::<Vec<_>>();
+        names.dedup();
+        names
+    }
+
+    /// All secrets of the one secret setting that is configured
+    fn secret_source(&self) -> Result<Vec<SecretSetting<'_>>, KeyError> {
+        let names = self.secret_names();
+        match names.len() {
+            0 => Err(KeyError::Missing),
+            1 => Ok(self.secret_settings()),
+            _ => Err(KeyError::Ambiguous(names)),
         }
     }
+
+    /// Name of the setting that the secret is configured with
+    fn secret_setting(&self) -> Option<&'static str> {
+        self.secret_names().first().copied()
+    }
+
+    /// Validate the secret settings. Secrets from the environment,
+    /// credentials and commands are only available to the running
+    /// service, so these are not read, and neither are files without
+    /// `read_files`.
+    fn check_secret(&self, read_files: bool) -> Result<(), KeyError> {
+        for setting in self.secret_source()? {
+            match setting.source {
+                Source::Inline => setting.read().map(|_| ())?,
+                Source::File if read_files => setting.read().map(|_| ())?,
+                Source::File | Source::Env | Source::Credential | Source::Command => {
+                    setting.check()?;
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
-#[derive(Debug, Deserialize)]
+/// Where a secret setting gets the secret from
+#[derive(Clone, Copy)]
+enum Source {
+    Inline,
+    File,
+    Env,
+    Credential,
+    Command,
+}
+
+struct SecretSetting<'a> {
+    name: &'static str,
+    /// The inline secret, or a path or name where it can be found
+    value: &'a [u8],
+    source: Source,
+    base64: bool,
+}
+
+impl SecretSetting<'_> {
+    /// Check the value without reading the secret
+    fn check(&self) -> Result<(), KeyError> {
+        let invalid = |message| {
+            Err(KeyError::InvalidSetting {
+                setting: self.name,
+                message,
+            })
+        };
+        match self.source {
+            Source::Env if self.value.is_empty() || self.value.contains(&b'=') => {
+                invalid("not a valid environment variable name")
+            }
+            Source::Credential if self.value.is_empty() || self.value.contains(&b'/') => {
+                invalid("not a valid credential name")
+            }
+            Source::Command if self.value.trim_ascii().is_empty() => invalid("empty command"),
+            _ => Ok(()),
+        }
+    }
+
+    fn read(&self) -> Result<Secret, KeyError> {
+        self.check()?;
+        let value = OsStr::from_bytes(self.value);
+        let secret = match self.source {
+            Source::Inline => Secret::from(self.value.to_vec()),
+            Source::File => read_secret_file(self.name, Path::new(value))?,
+            Source::Env => std::env::var_os(value)
+                .ok_or_else(|| KeyError::EnvNotSet {
+                    setting: self.name,
+                    variable: value.to_string_lossy().into_owned(),
+                })?
+                .into_vec()
+                .into(),
+            Source::Credential => {
+                let directory = std::env::var_os("CREDENTIALS_DIRECTORY")
+                    .ok_or(KeyError::NoCredentialsDirectory { setting: self.name })?;
+                read_secret_file(self.name, &Path::new(&directory).join(value))?
+            }
+            Source::Command => run_secret_command(self.name, value)?,
+        };
+        if self.base64 {
+            decode_base64(self.name, secret.expose().trim_ascii())
+        } else {
+            Ok(secret)
+        }
+    }
+}
+
+fn read_secret_file(setting: &'static str, path: &Path) -> Result<Secret, KeyError> {
+    let read = || -> std::io::Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        File::open(path)?.read_to_end(&mut buf)?;
+        Ok(buf)
+    };
+    read().map(Secret::from).map_err(|source| KeyError::Read {
+        setting,
+        path: path.display().to_string(),
+        source,
+    })
+}
+
+/// Runs `command` with `/bin/sh` and returns its output
+fn run_secret_command(setting: &'static str, command: &OsStr) -> Result<Secret, KeyError> {
+    let output = Command::new("/bin/sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::null())
+        .output()
+        .map_err(|source| KeyError::Command {
+            setting,
+            command: command.to_string_lossy().into_owned(),
+            source,
+        })?;
+    if !output.status.success() {
+        return Err(KeyError::CommandFailed {
+            setting,
+            command: command.to_string_lossy().into_owned(),
+            status: output.status,
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(output.stdout.into())
+}
+
+fn decode_base64(setting: &'static str, data: &[u8]) -> Result<Secret, KeyError> {
+    general_purpose::STANDARD
+        .decode(data)
+        .map(Secret::from)
+        .map_err(|source| KeyError::Base64 { setting, source })
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum KeyError {
+    #[error("No secret configured. Configure one of the secret parameters or key-file, lists must not be empty.")]
+    Missing,
+    #[error("Conflicting parameters {}. Configure exactly one of the secret parameters or key-file.", .0.join(", "))]
+    Ambiguous(Vec<&'static str>),
+    #[error("Invalid {setting}: {message}")]
+    InvalidSetting {
+        setting: &'static str,
+        message: &'static str,
+    },
+    #[error("name and alg are required unless key-file is configured")]
+    MissingNameOrAlg,
+    #[error("Cannot load key-file {path}: {source}")]
+    KeyFile {
+        path: String,
+        #[source]
+        source: keyfile::Error,
+    },
+    #[error("No key {name} in key-file {path}")]
+    NoSuchKey { path: String, name: String },
+    #[error("key-file {path} contains {count} keys, select one with name")]
+    NameRequired { path: String, count: usize },
+    #[error("alg {alg} differs from algorithm {key_file_alg} in key-file")]
+    AlgorithmMismatch { alg: String, key_file_alg: String },
+    /// The file of `secret-file`, `secret-credential` or their
+    /// base64 variants cannot be read
+    #[error("Failed to read the {setting} {path}: {source}")]
+    Read {
+        setting: &'static str,
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Environment variable {variable} of {setting} is not set")]
+    EnvNotSet {
+        setting: &'static str,
+        variable: String,
+    },
+    #[error("{setting} requires $CREDENTIALS_DIRECTORY, see systemd's LoadCredential=")]
+    NoCredentialsDirectory { setting: &'static str },
+    #[error("Cannot run the {setting} {command:?}: {source}")]
+    Command {
+        setting: &'static str,
+        command: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("The {setting} {command:?} failed with {status}: {stderr}")]
+    CommandFailed {
+        setting: &'static str,
+        command: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+    #[error("Invalid base64 in {setting}: {source}")]
+    Base64 {
+        setting: &'static str,
+        #[source]
+        source: base64::DecodeError,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct UpdateTask {
     pub key: String,
@@ -67,14 +540,797 @@ pub struct UpdateTask {
     pub ttl: Option<u32>,
     pub scope: Option<String>,
     pub neighbors: Option<HashMap<String, Ipv6Addr>>,
+    #[serde(rename = "withdraw-on-exit")]
+    pub withdraw_on_exit: Option<bool>,
+}
+
+/// Global `[defaults]` for records that set neither the value nor
+/// their key's `default-*` setting
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Defaults {
+    pub zone: Option<String>,
+    pub ttl: Option<u32>,
+    /// Up to one scope per address family
+    pub scope: Option<OneOrMore<String>>,
+}
+
+/// The scope of `scopes` that belongs to `af`
+#[must_use]
+pub fn family_scope(scopes: Option<&OneOrMore<String>>, af: AddressFamily) -> Option<&str> {
+    scopes?
+        .as_slice()
+        .iter()
+        .find(|scope| {
+            IpCidr::from_str(scope)
+                .is_ok_and(|scope| scope.is_ipv4() == (af == AddressFamily::IPv4))
+        })
+        .map(String::as_str)
+}
+
+/// A `[[record]]`: names that get the addresses of one or both
+/// families from an interface
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Record {
+    pub key: String,
+    #[serde(alias = "name")]
+    pub names: Option<OneOrMore<String>>,
+    pub interface: String,
+    pub zone: Option<String>,
+    pub ttl: Option<u32>,
+    /// Defaults to both
+    pub families: Option<Vec<AddressFamily>>,
+    pub ipv4: Option<FamilySettings>,
+    pub ipv6: Option<FamilySettings>,
+    pub neighbors: Option<HashMap<String, Ipv6Addr>>,
+    #[serde(rename = "withdraw-on-exit")]
+    pub withdraw_on_exit: Option<bool>,
+}
+
+/// Settings of a `[[record]]` for one address family
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FamilySettings {
+    /// Instead of the record's `interface`
+    pub interface: Option<String>,
+    pub scope: Option<String>,
+}
+
+impl Record {
+    #[must_use]
+    pub fn families(&self) -> &[AddressFamily] {
+        self.families
+            .as_deref()
+            .unwrap_or(&[AddressFamily::IPv4, AddressFamily::IPv6])
+    }
+
+    fn family(&self, af: AddressFamily) -> Option<&FamilySettings> {
+        match af {
+            AddressFamily::IPv4 => self.ipv4.as_ref(),
+            AddressFamily::IPv6 => self.ipv6.as_ref(),
+        }
+    }
+
+    /// One task per family and name, with the index of the name. The
+    /// neighbors get a task of their own.
+    fn expand(&self) -> Vec<(AddressFamily, Option<usize>, UpdateTask)> {
+        let names = self.names.as_ref().map_or(&[][..], OneOrMore::as_slice);
+        let mut tasks = vec![];
+        for &af in self.families() {
+            let settings = self.family(af).cloned().unwrap_or_default();
+            let interface = settings.interface.unwrap_or_else(|| self.interface.clone());
+            // Other placeholders have been expanded by the `Validator`
+            let expand = |value: &str| template::expand_interface(value, &interface);
+            let task = UpdateTask {
+                key: self.key.clone(),
+                name: None,
+                interface: interface.clone(),
+                zone: self.zone.as_deref().map(expand),
+                ttl: self.ttl,
+                scope: settings.scope,
+                neighbors: None,
+                withdraw_on_exit: self.withdraw_on_exit,
+            };
+            for (index, name) in names.iter().enumerate() {
+                let task = UpdateTask {
+                    name: Some(expand(name)),
+                    ..task.clone()
+                };
+                tasks.push((af, Some(index), task));
+            }
+            if let (AddressFamily::IPv6, Some(neighbors)) = (af, &self.neighbors) {
+                let neighbors = neighbors
+                    .iter()
+                    .map(|(name, addr)| (expand(name), *addr))
+                    .collect();
+                let task = UpdateTask {
+                    neighbors: Some(neighbors),
+                    ..task
+                };
+                tasks.push((af, None, task));
+            }
+        }
+        tasks
+    }
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(deny_unknown_fields)]
+pub struct Retry {
+    #[serde(rename = "initial-interval")]
+    pub initial_interval: Option<u64>,
+    #[serde(rename = "max-interval")]
+    pub max_interval: Option<u64>,
+    pub multiplier: Option<f64>,
+    pub jitter: Option<f64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[derive(Default)]
 pub struct Config {
+    /// Files whose keys and records are added, e.g.
+    /// `/etc/ifdyndnsd/conf.d/*.toml`. Relative paths start at the
+    /// directory of this file.
+    pub include: Option<Vec<String>>,
+    #[serde(default)]
     pub keys: HashMap<String, TsigKey>,
     pub a: Option<Vec<UpdateTask>>,
     pub aaaa: Option<Vec<UpdateTask>>,
+    pub record: Option<Vec<Record>>,
+    pub defaults: Option<Defaults>,
+    pub retry: Option<Retry>,
+    #[serde(rename = "verify-interval")]
+    pub verify_interval: Option<u64>,
+    #[serde(rename = "state-file")]
+    pub state_file: Option<String>,
+    /// Unix socket for `ifdyndnsd ctl`
+    #[serde(rename = "control-socket")]
+    pub control_socket: Option<String>,
+    /// Address to serve Prometheus metrics on
+    #[serde(rename = "metrics-listen")]
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+impl Config {
+    /// All update tasks, from `[[a]]`, `[[aaaa]]` and `[[record]]`
+    #[must_use]
+    pub fn tasks(&self) -> Vec<(AddressFamily, UpdateTask)> {
+        let a = self
+            .a
+            .iter()
+            .flatten()
+            .map(|a| (AddressFamily::IPv4, a.clone()));
+        let aaaa = self
+            .aaaa
+            .iter()
+            .flatten()
+            .map(|aaaa| (AddressFamily::IPv6, aaaa.clone()));
+        let records = self
+            .record
+            .iter()
+            .flatten()
+            .flat_map(Record::expand)
+            .map(|(af, _, task)| (af, task));
+        a.chain(aaaa).chain(records).collect()
+    }
+}
+
+/// A problem found while loading the configuration
+#[derive(Debug)]
+pub struct Problem {
+    /// Config file, which can be an included one
+    pub file: Option<String>,
+    /// Line in the config file
+    pub line: Option<usize>,
+    /// Path of the setting, e.g. `aaaa[1].scope`
+    pub location: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{file}: ")?;
+        }
+        if let Some(line) = self.line {
+            write!(f, "line {line}: ")?;
+        }
+        if let Some(location) = &self.location {
+            write!(f, "{location}: ")?;
+        }
+        write!(f, "{}", self.message)
+    }
+}
+
+/// All problems that prevent the configuration from being used
+#[derive(Debug)]
+pub struct Problems(pub Vec<Problem>);
+
+impl fmt::Display for Problems {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, problem) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{problem}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Cannot read {path}: {source}")]
+    Read {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    /// Invalid TOML or settings that don't fit into `Config`
+    #[error("{problem}")]
+    Parse {
+        problem: Problem,
+        #[source]
+        source: Box<toml::de::Error>,
+    },
+    #[error("{0}")]
+    Invalid(Problems),
+    #[error("Cannot write the configuration: {0}")]
+    Serialize(#[from] toml::ser::Error),
+}
+
+/// Path segment of a setting
+#[derive(Clone, Copy)]
+enum Key<'a> {
+    Name(&'a str),
+    Index(usize),
+}
+
+fn format_path(path: &[Key<'_>]) -> String {
+    path.iter()
+        .enumerate()
+        .map(|(i, key)| {
+            let separator = if i > 0 { "." } else { "" };
+            match key {
+                Key::Name(name)
+                    if name
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') =>
+                {
+                    format!("{separator}{name}")
+                }
+                Key::Name(name) => format!("{separator}{name:?}"),
+                Key::Index(index) => format!("[{index}]"),
+            }
+        })
+        .collect()
+}
+
+/// Find the span of the setting at `path`, or of its closest parent
+fn locate(table: &DeTable<'_>, path: &[Key<'_>]) -> Option<Range<usize>> {
+    let (Key::Name(name), rest) = path.split_first()? else {
+        return None;
+    };
+    let (_, value) = table.iter().find(|(key, _)| key.get_ref() == name)?;
+    Some(locate_value(value, rest))
+}
+
+fn locate_value(value: &Spanned<DeValue<'_>>, path: &[Key<'_>]) -> Range<usize> {
+    let inner = match (path.split_first(), value.get_ref()) {
+        (Some((Key::Name(_), _)), DeValue::Table(table)) => locate(table, path),
+        (Some((Key::Index(index), rest)), DeValue::Array(array)) => {
+            array.get(*index).map(|value| locate_value(value, rest))
+        }
+        _ => None,
+    };
+    inner.unwrap_or_else(|| value.span())
+}
+
+/// Path of a name of the `[[record]]` at `index`, which is configured
+/// with `names_key`, or of all its names
+fn record_name_path(
+    index: usize,
+    names_key: &'static str,
+    name_index: Option<usize>,
+) -> Vec<Key<'static>> {
+    let mut path = vec![Key::Name("record"), Key::Index(index), Key::Name(names_key)];
+    path.extend(name_index.map(Key::Index));
+    path
+}
+
+/// Where the settings of an `UpdateTask` are configured
+struct TaskPaths<'a> {
+    table: Vec<Key<'a>>,
+    key: Vec<Key<'a>>,
+    name: Vec<Key<'a>>,
+    zone: Vec<Key<'a>>,
+    scope: Vec<Key<'a>>,
+    neighbors: Vec<Key<'a>>,
+}
+
+impl<'a> TaskPaths<'a> {
+    /// Settings of the table at `path`
+    fn new(path: &[Key<'a>]) -> Self {
+        let field = |name| {
+            let mut field = path.to_vec();
+            field.push(Key::Name(name));
+            field
+        };
+        TaskPaths {
+            table: path.to_vec(),
+            key: field("key"),
+            name: field("name"),
+            zone: field("zone"),
+            scope: field("scope"),
+            neighbors: field("neighbors"),
+        }
+    }
+}
+
+/// Collects problems with their location
+struct Validator<'a> {
+    filename: &'a str,
+    source: &'a str,
+    document: Option<Spanned<DeTable<'a>>>,
+    problems: Vec<Problem>,
+    /// Problems of a `[[record]]` are found once per name and family
+    /// but only reported once
+    reported: HashSet<String>,
+    /// Settings with placeholders that could not be expanded, which
+    /// need no further checks
+    unexpanded: HashSet<String>,
+    /// Whether the configuration is checked elsewhere than on the
+    /// host that runs it, see [`load_offline`]
+    offline: bool,
+}
+
+impl<'a> Validator<'a> {
+    fn new(file: &'a ConfigFile) -> Self {
+        let source = &file.source;
+        Validator {
+            filename: &file.filename,
+            source,
+            document: DeTable::parse(source).ok(),
+            problems: vec![],
+            reported: HashSet::new(),
+            unexpanded: HashSet::new(),
+            offline: false,
+        }
+    }
+
+    fn problem(&self, path: &[Key<'_>], message: String) -> Problem {
+        let line = self
+            .document
+            .as_ref()
+            .and_then(|document| locate(document.get_ref(), path))
+            .map(|span| self.source[..span.start].matches('\n').count() + 1);
+        Problem {
+            file: Some(self.filename.to_string()),
+            line,
+            location: Some(format_path(path)),
+            message,
+        }
+    }
+
+    fn error(&mut self, path: &[Key<'_>], message: String) {
+        if self.unexpanded.contains(&format_path(path)) {
+            return;
+        }
+        let problem = self.problem(path, message);
+        if self.reported.insert(problem.to_string()) {
+            self.problems.push(problem);
+        }
+    }
+
+    fn warn(&mut self, path: &[Key<'_>], message: String) {
+        let problem = self.problem(path, message);
+        if self.reported.insert(problem.to_string()) {
+            warn!("{problem}");
+        }
+    }
+
+    /// Replace the placeholders in names and zones, see [`template`].
+    /// `{interface}` of a `[[record]]` is replaced per family by
+    /// `Record::expand()`.
+    fn expand(&mut self, config: &mut Config) {
+        for (section, tasks) in [("a", &mut config.a), ("aaaa", &mut config.aaaa)] {
+            for (index, task) in tasks.iter_mut().flatten().enumerate() {
+                let paths = TaskPaths::new(&[Key::Name(section), Key::Index(index)]);
+                let interface = Some(task.interface.as_str());
+                if let Some(name) = &mut task.name {
+                    self.expand_value(&paths.name, name, interface);
+                }
+                if let Some(zone) = &mut task.zone {
+                    self.expand_value(&paths.zone, zone, interface);
+                }
+                self.expand_neighbors(&paths.neighbors, &mut task.neighbors, interface);
+            }
+        }
+        for (index, record) in config.record.iter_mut().flatten().enumerate() {
+            let paths = TaskPaths::new(&[Key::Name("record"), Key::Index(index)]);
+            let names_key = self.names_key(index);
+            match &mut record.names {
+                Some(OneOrMore::One(name)) => {
+                    let path = record_name_path(index, names_key, None);
+                    self.expand_value(&path, name, None);
+                }
+                Some(OneOrMore::More(names)) => {
+                    for (name_index, name) in names.iter_mut().enumerate() {
+                        let path = record_name_path(index, names_key, Some(name_index));
+                        self.expand_value(&path, name, None);
+                    }
+                }
+                None => {}
+            }
+            if let Some(zone) = &mut record.zone {
+                self.expand_value(&paths.zone, zone, None);
+            }
+            self.expand_neighbors(&paths.neighbors, &mut record.neighbors, None);
+        }
+    }
+
+    /// `name` or `names`, whichever the `[[record]]` at `index` uses
+    fn names_key(&self, index: usize) -> &'static str {
+        let record = [Key::Name("record"), Key::Index(index)];
+        let name = [record[0], record[1], Key::Name("name")];
+        let uses_name = self.document.as_ref().is_some_and(|document| {
+            locate(document.get_ref(), &name) != locate(document.get_ref(), &record)
+        });
+        if uses_name {
+            "name"
+        } else {
+            "names"
+        }
+    }
+
+    fn expand_value(&mut self, path: &[Key<'_>], value: &mut String, interface: Option<&str>) {
+        match template::expand(value, interface) {
+            Ok(expanded) => *value = expanded,
+            Err(e) => self.unexpanded_error(path, &e),
+        }
+    }
+
+    fn unexpanded_error(&mut self, path: &[Key<'_>], e: &template::Error) {
+        if !(self.offline && e.depends_on_host()) {
+            self.error(path, e.to_string());
+        }
+        self.unexpanded.insert(format_path(path));
+    }
+
+    fn expand_neighbors(
+        &mut self,
+        path: &[Key<'_>],
+        neighbors: &mut Option<HashMap<String, Ipv6Addr>>,
+        interface: Option<&str>,
+    ) {
+        let Some(neighbors) = neighbors else {
+            return;
+        };
+        *neighbors = neighbors
+            .drain()
+            .map(|(name, addr)| match template::expand(&name, interface) {
+                Ok(expanded) => (expanded, addr),
+                Err(e) => {
+                    let mut name_path = path.to_vec();
+                    name_path.push(Key::Name(&name));
+                    self.unexpanded_error(&name_path, &e);
+                    (name, addr)
+                }
+            })
+            .collect();
+    }
+
+    /// Validate the settings of one file, where tasks can use `keys`
+    /// from all files. `names` collects the updated names of all files.
+    fn validate(
+        &mut self,
+        config: &Config,
+        keys: &HashMap<String, TsigKey>,
+        defaults: &Defaults,
+        names: &mut HashMap<(String, AddressFamily), (String, String)>,
+    ) {
+        for (key_name, key) in &config.keys {
+            self.validate_key(key_name, key);
+        }
+        if let Some(defaults) = &config.defaults {
+            self.validate_defaults(
+                &[Key::Name("defaults")],
+                ("zone", defaults.zone.as_ref()),
+                ("scope", defaults.scope.as_ref()),
+            );
+        }
+
+        for (section, af) in [("a", AddressFamily::IPv4), ("aaaa", AddressFamily::IPv6)] {
+            let tasks = match af {
+                AddressFamily::IPv4 => &config.a,
+                AddressFamily::IPv6 => &config.aaaa,
+            };
+            for (index, task) in tasks.iter().flatten().enumerate() {
+                let paths = TaskPaths::new(&[Key::Name(section), Key::Index(index)]);
+                self.validate_task(keys, defaults, &paths, task, af, names);
+            }
+        }
+        for (index, record) in config.record.iter().flatten().enumerate() {
+            self.validate_record(keys, defaults, index, record, names);
+        }
+
+        if let Err(e) = retry::Policy::new(config.retry.as_ref()) {
+            self.error(&[Key::Name("retry")], e.to_string());
+        }
+        if config
+            .verify_interval
+            .is_some_and(|verify_interval| verify_interval < MIN_VERIFY_INTERVAL)
+        {
+            self.error(
+                &[Key::Name("verify-interval")],
+                format!("Must be at least {MIN_VERIFY_INTERVAL} seconds"),
+            );
+        }
+    }
+
+    fn validate_key(&mut self, key_name: &str, key: &TsigKey) {
+        let path = [Key::Name("keys"), Key::Name(key_name)];
+        let field = |name| [path[0], path[1], Key::Name(name)];
+        self.validate_defaults(
+            &path,
+            ("default-zone", key.default_zone.as_ref()),
+            ("default-scope", key.default_scope.as_ref()),
+        );
+        // Settings that are either configured or come from the key file
+        let setting = |name, value: &Option<String>| {
+            if value.is_some() || key.key_file.is_none() {
+                field(name)
+            } else {
+                field("key-file")
+            }
+        };
+
+        for (setting, secret_file) in key.secret_files() {
+            // Unreadable files are reported below
+            let Ok(metadata) = std::fs::metadata(secret_file) else {
+                continue;
+            };
+            let mode = metadata.permissions().mode();
+            if mode & 0o004 != 0 {
+                self.error(
+                    &field(setting),
+                    format!("{secret_file} is readable by everyone, restrict it with chmod o-r"),
+                );
+            } else if mode & 0o040 != 0 {
+                self.warn(
+                    &field(setting),
+                    format!("{secret_file} is readable by its group"),
+                );
+            }
+        }
+
+        let (name, alg) = if key.key_file.is_some() {
+            if self.offline {
+                // Name and algorithm are in the key file
+                return;
+            }
+            match key.credentials() {
+                Ok(credentials) => (credentials.name, credentials.alg),
+                Err(e @ KeyError::AlgorithmMismatch { .. }) => {
+                    self.error(&field("alg"), e.to_string());
+                    return;
+                }
+                Err(e) => {
+                    self.error(&field("key-file"), e.to_string());
+                    return;
+                }
+            }
+        } else {
+            if let Err(e) = key.check_secret(!self.offline) {
+                match key.secret_setting() {
+                    Some(secret_setting) => self.error(&field(secret_setting), e.to_string()),
+                    None => self.error(&path, e.to_string()),
+                }
+            }
+            let (Some(name), Some(alg)) = (&key.name, &key.alg) else {
+                self.error(&path, KeyError::MissingNameOrAlg.to_string());
+                return;
+            };
+            (name.clone(), alg.clone())
+        };
+
+        if let Err(e) = Name::from_str(&name) {
+            self.error(
+                &setting("name", &key.name),
+                format!("Invalid key name: {e}"),
+            );
+        }
+        match Name::from_str(&alg).map(TsigAlgorithm::from_name) {
+            Ok(alg) if alg.supported() => {}
+            _ => self.error(
+                &setting("alg", &key.alg),
+                format!(
+                    "Unsupported algorithm {alg:?}, use one of hmac-sha256, hmac-sha384 or hmac-sha512"
+                ),
+            ),
+        }
+    }
+
+    /// Check the zone and scope settings of a key or `[defaults]`
+    fn validate_defaults(
+        &mut self,
+        path: &[Key<'_>],
+        (zone_setting, zone): (&str, Option<&String>),
+        (scope_setting, scopes): (&str, Option<&OneOrMore<String>>),
+    ) {
+        let field = |name| {
+            let mut field = path.to_vec();
+            field.push(Key::Name(name));
+            field
+        };
+        if let Some(zone) = zone {
+            if let Err(e) = Name::from_str(zone) {
+                self.error(&field(zone_setting), format!("Invalid zone: {e}"));
+            }
+        }
+
+        let mut families = HashSet::new();
+        for (index, scope) in scopes
+            .map_or(&[][..], OneOrMore::as_slice)
+            .iter()
+            .enumerate()
+        {
+            let mut scope_path = field(scope_setting);
+            if let Some(OneOrMore::More(_)) = scopes {
+                scope_path.push(Key::Index(index));
+            }
+            match IpCidr::from_str(scope) {
+                Ok(scope) => {
+                    if !families.insert(scope.is_ipv4()) {
+                        self.error(
+                            &scope_path,
+                            format!("More than one scope for the address family of {scope}"),
+                        );
+                    }
+                }
+                Err(e) => self.error(&scope_path, format!("Invalid scope: {e}")),
+            }
+        }
+    }
+
+    fn validate_record(
+        &mut self,
+        keys: &HashMap<String, TsigKey>,
+        defaults: &Defaults,
+        index: usize,
+        record: &Record,
+        names: &mut HashMap<(String, AddressFamily), (String, String)>,
+    ) {
+        let path = [Key::Name("record"), Key::Index(index)];
+        let field = |name| [path[0], path[1], Key::Name(name)];
+
+        let families = record.families();
+        if families.is_empty() {
+            self.error(&field("families"), "No address family".to_string());
+        }
+        for (af, section) in [(AddressFamily::IPv4, "ipv4"), (AddressFamily::IPv6, "ipv6")] {
+            if record.family(af).is_some() && !families.contains(&af) {
+                self.warn(
+                    &field(section),
+                    format!("Unused because {section} is not in families"),
+                );
+            }
+        }
+        if record.neighbors.is_some() && !families.contains(&AddressFamily::IPv6) {
+            self.error(
+                &field("neighbors"),
+                "Neighbors require ipv6 in families".to_string(),
+            );
+        }
+        if record.names.is_none() && record.neighbors.is_none() {
+            self.error(&path, "Neither names nor neighbors configured".to_string());
+        }
+
+        let names_key = self.names_key(index);
+        for (af, name_index, task) in record.expand() {
+            let mut paths = TaskPaths::new(&path);
+            paths.name = match (&record.names, name_index) {
+                (Some(OneOrMore::More(_)), Some(name_index)) => {
+                    record_name_path(index, names_key, Some(name_index))
+                }
+                _ => record_name_path(index, names_key, None),
+            };
+            let section = match af {
+                AddressFamily::IPv4 => "ipv4",
+                AddressFamily::IPv6 => "ipv6",
+            };
+            paths.scope = vec![path[0], path[1], Key::Name(section), Key::Name("scope")];
+            self.validate_task(keys, defaults, &paths, &task, af, names);
+        }
+    }
+
+    fn validate_task(
+        &mut self,
+        keys: &HashMap<String, TsigKey>,
+        defaults: &Defaults,
+        paths: &TaskPaths<'_>,
+        task: &UpdateTask,
+        af: AddressFamily,
+        names: &mut HashMap<(String, AddressFamily), (String, String)>,
+    ) {
+        let key = keys.get(&task.key);
+        if key.is_none() {
+            self.error(&paths.key, format!("No such key: {}", task.key));
+        }
+        let key_default_ttl = key.and_then(|key| key.default_ttl);
+        if task.ttl.or(key_default_ttl).or(defaults.ttl).is_none() {
+            self.warn(
+                &paths.table,
+                "No ttl, default-ttl of the key or ttl in [defaults], using a ttl of 0".to_string(),
+            );
+        }
+
+        if let Some(scope) = &task.scope {
+            match IpCidr::from_str(scope) {
+                Ok(scope) if scope.is_ipv4() == (af == AddressFamily::IPv4) => {}
+                Ok(scope) => self.error(
+                    &paths.scope,
+                    format!("Scope {scope} doesn't match address family {af:?}"),
+                ),
+                Err(e) => self.error(&paths.scope, format!("Invalid scope: {e}")),
+            }
+        }
+
+        if af == AddressFamily::IPv4 && task.neighbors.is_some() {
+            self.error(
+                &paths.neighbors,
+                "Neighbors are not supported on IPv4".to_string(),
+            );
+        }
+
+        // Invalid defaults are reported with the key or `[defaults]`
+        let default_zone = key
+            .and_then(|key| key.default_zone.as_deref())
+            .or(defaults.zone.as_deref())
+            .and_then(|zone| Name::from_str(zone).ok());
+        let zone = match task.zone.as_deref().map(Name::from_str) {
+            Some(Ok(zone)) => Some(zone),
+            Some(Err(e)) => {
+                self.error(&paths.zone, format!("Invalid zone: {e}"));
+                None
+            }
+            None => default_zone,
+        };
+
+        let neighbor_names = task.neighbors.iter().flat_map(HashMap::keys);
+        for name in task.name.iter().chain(neighbor_names) {
+            let name_path = if task.name.as_ref() == Some(name) {
+                paths.name.clone()
+            } else {
+                let mut name_path = paths.neighbors.clone();
+                name_path.push(Key::Name(name));
+                name_path
+            };
+
+            match Name::from_str(name) {
+                Ok(parsed) => {
+                    if let Some(zone) = &zone {
+                        if !zone.zone_of(&parsed) {
+                            self.warn(&name_path, format!("{name} is outside of zone {zone}"));
+                        }
+                    }
+                }
+                Err(e) => self.error(&name_path, format!("Invalid name: {e}")),
+            }
+
+            let location = (self.filename.to_string(), format_path(&name_path));
+            if let Some((file, other)) = names.insert((name.to_lowercase(), af), location) {
+                let other = if file == self.filename {
+                    other
+                } else {
+                    format!("{other} in {file}")
+                };
+                self.warn(&name_path, format!("{name} is also updated by {other}"));
+            }
+        }
+    }
 }
 
 /// # Errors
@@ -84,11 +1340,632 @@ pub struct Config {
 /// - `config_file` does not exist or the user does not have permission to read it.
 /// - Data inside `config_file` is not valid UTF-8.
 /// - Data inside `config_file` could not be deserialized as TOML.
+/// - Settings are invalid, e.g. keys that are referenced don't exist
+///   or secrets cannot be read.
+/// - A key or global setting is defined in more than one file.
+///
+/// `config_file` can also be a directory, whose `*.toml` files are
+/// read in alphabetical order. Keys and records of all files,
+/// including those from `include`, are merged.
+///
+/// Questionable settings are logged as warnings.
+pub fn load(filename: &str) -> Result<Config, Error> {
+    load_files(filename, false)
+}
+
+/// Like [`load`], for checking a configuration elsewhere than on the
+/// host that runs it, e.g. in a Nix build. Secret and key files are
+/// not read, and placeholders whose value is only known on that host,
+/// like `{machine-id-short}` and `{env:VAR}`, are not checked.
 ///
-pub fn load(filename: &str) -> Result<Config, String> {
-    let mut f = File::open(filename).map_err(|e| format!("{e}"))?;
+/// # Errors
+///
+/// Will return `Err` like [`load`], except for the settings above.
+pub fn load_offline(filename: &str) -> Result<Config, Error> {
+    load_files(filename, true)
+}
+
+fn load_files(filename: &str, offline: bool) -> Result<Config, Error> {
+    let filenames = if Path::new(filename).is_dir() {
+        directory(filename)?
+    } else {
+        vec![filename.to_string()]
+    };
+
+    let mut files = vec![];
+    for filename in filenames {
+        let (file, config) = ConfigFile::read(filename, false)?;
+        let includes = file.includes(&config)?;
+        files.push((file, config));
+        for filename in includes {
+            files.push(ConfigFile::read(filename, true)?);
+        }
+    }
+    merge(files, offline)
+}
+
+/// Validate a configuration that has been built in memory, e.g. from
+/// command line arguments. Problems have no line numbers.
+///
+/// # Errors
+///
+/// Will return `Err` if settings are invalid, see [`load`].
+pub fn validate(config: Config) -> Result<Config, Error> {
+    let file = ConfigFile {
+        filename: "(command line)".to_string(),
+        source: String::new(),
+        included: false,
+    };
+    merge(vec![(file, config)], false)
+}
+
+/// The `*.toml` files in `dir`, in alphabetical order
+fn directory(dir: &str) -> Result<Vec<String>, Error> {
+    let read_error = |source| Error::Read {
+        path: dir.to_string(),
+        source,
+    };
+    let mut filenames = vec![];
+    for entry in std::fs::read_dir(dir).map_err(read_error)? {
+        let path = entry.map_err(read_error)?.path();
+        if path.extension() == Some(OsStr::new("toml")) && path.is_file() {
+            filenames.push(path.to_string_lossy().into_owned());
+        }
+    }
+    filenames.sort();
+    Ok(filenames)
+}
+
+/// One file of the configuration
+struct ConfigFile {
+    filename: String,
+    source: String,
+    /// Included by another file
+    included: bool,
+}
+
+impl ConfigFile {
+    fn read(filename: String, included: bool) -> Result<(Self, Config), Error> {
+        let source = read(&filename)?;
+        let file = ConfigFile {
+            filename,
+            source,
+            included,
+        };
+        let config = toml::from_str(&file.source).map_err(|e| file.parse_error(e))?;
+        Ok((file, config))
+    }
+
+    /// Files matching the `include` patterns. Includes of included
+    /// files are reported by `merge()`.
+    fn includes(&self, config: &Config) -> Result<Vec<String>, Error> {
+        let dir = Path::new(&self.filename)
+            .parent()
+            .unwrap_or_else(|| Path::new(""));
+        let mut validator = Validator::new(self);
+        let mut filenames = vec![];
+        for (index, pattern) in config.include.iter().flatten().enumerate() {
+            let path = [Key::Name("include"), Key::Index(index)];
+            let pattern = dir.join(pattern).to_string_lossy().into_owned();
+            let matches = match glob::glob(&pattern) {
+                Ok(matches) => matches,
+                Err(e) => {
+                    validator.error(&path, format!("Invalid pattern: {e}"));
+                    continue;
+                }
+            };
+            let count = filenames.len();
+            for entry in matches {
+                let entry = entry.map_err(|e| Error::Read {
+                    path: e.path().to_string_lossy().into_owned(),
+                    source: e.into(),
+                })?;
+                filenames.push(entry.to_string_lossy().into_owned());
+            }
+            // An empty conf.d is fine, a missing file probably not
+            if filenames.len() == count && !pattern.contains(['*', '?', '[']) {
+                validator.error(&path, format!("No such file: {pattern}"));
+            }
+        }
+        if validator.problems.is_empty() {
+            Ok(filenames)
+        } else {
+            Err(Error::Invalid(Problems(validator.problems)))
+        }
+    }
+
+    fn parse_error(&self, e: toml::de::Error) -> Error {
+        let line = e
+            .span()
+            .map(|span| self.source[..span.start].matches('\n').count() + 1);
+        Error::Parse {
+            problem: Problem {
+                file: Some(self.filename.clone()),
+                line,
+                location: None,
+                message: e.message().to_string(),
+            },
+            source: Box::new(e),
+        }
+    }
+}
+
+fn read(filename: &str) -> Result<String, Error> {
+    let read_error = |source| Error::Read {
+        path: filename.to_string(),
+        source,
+    };
+    let mut f = File::open(filename).map_err(read_error)?;
     let mut buf = String::new();
-    f.read_to_string(&mut buf).map_err(|e| format!("{e}"))?;
-    let config = toml::from_str(&buf).map_err(|e| format!("{e}"))?;
-    Ok(config)
+    f.read_to_string(&mut buf).map_err(read_error)?;
+    Ok(buf)
+}
+
+/// Validate all files and merge them into one `Config`
+fn merge(files: Vec<(ConfigFile, Config)>, offline: bool) -> Result<Config, Error> {
+    let (files, mut configs): (Vec<_>, Vec<_>) = files.into_iter().unzip();
+    let mut validators = vec![];
+    for (file, config) in files.iter().zip(&mut configs) {
+        let mut validator = Validator::new(file);
+        validator.offline = offline;
+        validator.expand(config);
+        validators.push(validator);
+    }
+
+    // Keys and global settings may only be defined once
+    let mut defined: HashMap<String, &str> = HashMap::new();
+    for ((validator, file), config) in validators.iter_mut().zip(&files).zip(&configs) {
+        if file.included && config.include.is_some() {
+            validator.error(
+                &[Key::Name("include")],
+                "Included files cannot include others".to_string(),
+            );
+        }
+        let mut paths = config
+            .keys
+            .keys()
+            .map(|name| vec![Key::Name("keys"), Key::Name(name)])
+            .collect::<Vec<_>>();
+        let globals = [
+            ("retry", config.retry.is_some()),
+            ("verify-interval", config.verify_interval.is_some()),
+            ("state-file", config.state_file.is_some()),
+            ("control-socket", config.control_socket.is_some()),
+            ("metrics-listen", config.metrics_listen.is_some()),
+            ("defaults", config.defaults.is_some()),
+        ];
+        for (name, set) in globals {
+            if set {
+                paths.push(vec![Key::Name(name)]);
+            }
+        }
+        for path in paths {
+            let location = format_path(&path);
+            if let Some(other) = defined.get(&location) {
+                validator.error(&path, format!("Already defined in {other}"));
+            } else {
+                defined.insert(location, &file.filename);
+            }
+        }
+    }
+
+    let mut keys = HashMap::new();
+    for config in &configs {
+        for (name, key) in &config.keys {
+            keys.entry(name.clone()).or_insert_with(|| key.clone());
+        }
+    }
+    let defaults = configs
+        .iter()
+        .find_map(|config| config.defaults.clone())
+        .unwrap_or_default();
+    // (name, record type) -> (file, path)
+    let mut names = HashMap::new();
+    let mut problems = vec![];
+    for (mut validator, config) in validators.into_iter().zip(&configs) {
+        validator.validate(config, &keys, &defaults, &mut names);
+        problems.append(&mut validator.problems);
+    }
+    if !problems.is_empty() {
+        return Err(Error::Invalid(Problems(problems)));
+    }
+
+    let mut merged = Config {
+        keys,
+        ..Config::default()
+    };
+    for config in configs {
+        merged
+            .a
+            .get_or_insert_with(Vec::new)
+            .extend(config.a.into_iter().flatten());
+        merged
+            .aaaa
+            .get_or_insert_with(Vec::new)
+            .extend(config.aaaa.into_iter().flatten());
+        merged
+            .record
+            .get_or_insert_with(Vec::new)
+            .extend(config.record.into_iter().flatten());
+        merged.defaults = merged.defaults.or(config.defaults);
+        merged.retry = merged.retry.or(config.retry);
+        merged.verify_interval = merged.verify_interval.or(config.verify_interval);
+        merged.state_file = merged.state_file.or(config.state_file);
+        merged.control_socket = merged.control_socket.or(config.control_socket);
+        merged.metrics_listen = merged.metrics_listen.or(config.metrics_listen);
+    }
+    Ok(merged)
+}
+
+/// Rewrite the `[[a]]` and `[[aaaa]]` sections of `filename` as
+/// `[[record]]` sections. An `[[a]]` and an `[[aaaa]]` that only
+/// differ in their scope become one record, and so do records that
+/// only differ in their name. Comments are not preserved, and
+/// included files are left as they are.
+///
+/// # Errors
+///
+/// Will return `Err` if `filename` cannot be loaded, see [`load`].
+pub fn convert(filename: &str) -> Result<String, Error> {
+    load(filename)?;
+    let (file, _) = ConfigFile::read(filename.to_string(), false)?;
+    let mut document: toml::Table =
+        toml::from_str(&file.source).map_err(|e| file.parse_error(e))?;
+
+    let mut entries = |section| match document.remove(section) {
+        Some(toml::Value::Array(entries)) => entries,
+        _ => vec![],
+    };
+    let a = entries("a");
+    let aaaa = entries("aaaa");
+    let mut existing = entries("record");
+
+    let mut records: Vec<Converted> = vec![];
+    for entry in a.into_iter().filter_map(table) {
+        records.push(Converted::new(AddressFamily::IPv4, entry));
+    }
+    for entry in aaaa.into_iter().filter_map(table) {
+        let record = Converted::new(AddressFamily::IPv6, entry);
+        let pair = records.iter_mut().find(|a| {
+            a.families == [AddressFamily::IPv4]
+                && a.common == record.common
+                && a.names == record.names
+        });
+        match pair {
+            Some(pair) => {
+                pair.families.push(AddressFamily::IPv6);
+                pair.ipv6 = record.ipv6;
+                pair.neighbors = record.neighbors;
+            }
+            None => records.push(record),
+        }
+    }
+
+    let mut merged: Vec<Converted> = vec![];
+    for record in records {
+        let same = merged.iter_mut().find(|other| {
+            !other.names.is_empty()
+                && !record.names.is_empty()
+                && other.neighbors.is_none()
+                && record.neighbors.is_none()
+                && other.common == record.common
+                && other.families == record.families
+                && other.ipv4 == record.ipv4
+                && other.ipv6 == record.ipv6
+        });
+        match same {
+            Some(same) => same.names.extend(record.names),
+            None => merged.push(record),
+        }
+    }
+
+    existing.extend(merged.into_iter().map(|record| record.into_table().into()));
+    if !existing.is_empty() {
+        document.insert("record".to_string(), existing.into());
+    }
+    Ok(toml::to_string(&document)?)
+}
+
+fn table(value: toml::Value) -> Option<toml::Table> {
+    match value {
+        toml::Value::Table(table) => Some(table),
+        _ => None,
+    }
+}
+
+/// A `[[record]]` built from `[[a]]` and `[[aaaa]]` entries
+struct Converted {
+    /// Settings that are copied as they are
+    common: toml::Table,
+    names: Vec<toml::Value>,
+    families: Vec<AddressFamily>,
+    ipv4: Option<toml::Value>,
+    ipv6: Option<toml::Value>,
+    neighbors: Option<toml::Value>,
+}
+
+impl Converted {
+    fn new(af: AddressFamily, mut entry: toml::Table) -> Self {
+        let names = entry.remove("name").into_iter().collect();
+        let scope = entry.remove("scope");
+        let neighbors = entry.remove("neighbors");
+        let (ipv4, ipv6) = match af {
+            AddressFamily::IPv4 => (scope, None),
+            AddressFamily::IPv6 => (None, scope),
+        };
+        Converted {
+            common: entry,
+            names,
+            families: vec![af],
+            ipv4,
+            ipv6,
+            neighbors,
+        }
+    }
+
+    fn into_table(self) -> toml::Table {
+        let mut table = self.common;
+        let mut names = self.names;
+        if names.len() == 1 {
+            table.insert("name".to_string(), names.remove(0));
+        } else if !names.is_empty() {
+            table.insert("names".to_string(), names.into());
+        }
+        if let [af] = self.families[..] {
+            let family = match af {
+                AddressFamily::IPv4 => "ipv4",
+                AddressFamily::IPv6 => "ipv6",
+            };
+            table.insert("families".to_string(), vec![family].into());
+        }
+        for (family, scope) in [("ipv4", self.ipv4), ("ipv6", self.ipv6)] {
+            if let Some(scope) = scope {
+                let mut settings = toml::Table::new();
+                settings.insert("scope".to_string(), scope);
+                table.insert(family.to_string(), settings.into());
+            }
+        }
+        if let Some(neighbors) = self.neighbors {
+            table.insert("neighbors".to_string(), neighbors);
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[keys.k]
+server = "127.0.0.1"
+name = "k"
+alg = "hmac-sha256"
+secret-base64 = "dG9wc2VjcmV0"
+
+[[a]]
+key = "k"
+name = "host.example.net"
+zone = "example.net"
+interface = "ppp0"
+ttl = 60
+
+[[aaaa]]
+key = "k"
+name = "host.example.net"
+zone = "example.net"
+interface = "ppp0"
+ttl = 60
+scope = "2000::/3"
+
+[[aaaa]]
+key = "k"
+interface = "eth0"
+neighbors."nas.example.net" = "::2"
+
+[[a]]
+key = "k"
+name = "www.example.net"
+zone = "example.net"
+interface = "ppp0"
+ttl = 60
+"#;
+
+    /// Tasks in a stable order
+    fn sorted(tasks: &[(AddressFamily, UpdateTask)]) -> Vec<String> {
+        let mut tasks = tasks
+            .iter()
+            .map(|task| format!("{task:?}"))
+            .collect::<Vec<_>>();
+        tasks.sort();
+        tasks
+    }
+
+    #[test]
+    fn convert_keeps_tasks() {
+        let path =
+            std::env::temp_dir().join(format!("ifdyndnsd-convert-{}.toml", std::process::id()));
+        std::fs::write(&path, CONFIG).unwrap();
+        let filename = path.to_str().unwrap();
+        let original = load(filename).unwrap();
+        let converted = convert(filename);
+        std::fs::remove_file(&path).unwrap();
+
+        let converted: Config = toml::from_str(&converted.unwrap()).unwrap();
+        assert!(converted.a.is_none() && converted.aaaa.is_none());
+        // host with both families, www and the neighbors
+        assert_eq!(converted.record.as_ref().map(Vec::len), Some(3));
+        assert_eq!(sorted(&converted.tasks()), sorted(&original.tasks()));
+    }
+
+    #[test]
+    fn interface_per_family() {
+        let config = toml::from_str(
+            r#"
+[keys.k]
+server = "127.0.0.1"
+name = "k"
+alg = "hmac-sha256"
+secret-base64 = "dG9wc2VjcmV0"
+
+[[record]]
+key = "k"
+name = "{interface}.example.net"
+zone = "example.net"
+ttl = 60
+interface = "ppp0"
+ipv6 = { interface = "eth0" }
+neighbors."nas-{interface}.example.net" = "::2"
+"#,
+        )
+        .unwrap();
+        let tasks = validate(config).unwrap().tasks();
+        let names = tasks
+            .iter()
+            .map(|(af, task)| {
+                let name = task.name.clone();
+                let neighbors = task.neighbors.iter().flat_map(HashMap::keys).cloned();
+                (
+                    *af,
+                    task.interface.as_str(),
+                    name.into_iter().chain(neighbors).collect(),
+                )
+            })
+            .collect::<Vec<(_, _, Vec<_>)>>();
+        assert_eq!(
+            names,
+            [
+                (
+                    AddressFamily::IPv4,
+                    "ppp0",
+                    vec!["ppp0.example.net".to_string()]
+                ),
+                (
+                    AddressFamily::IPv6,
+                    "eth0",
+                    vec!["eth0.example.net".to_string()]
+                ),
+                (
+                    AddressFamily::IPv6,
+                    "eth0",
+                    vec!["nas-eth0.example.net".to_string()]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn verify_interval_minimum() {
+        let config = |verify_interval| {
+            let source = format!("verify-interval = {verify_interval}\n{CONFIG}");
+            validate(toml::from_str(&source).unwrap())
+        };
+        assert!(config(60).is_ok());
+        let e = config(0).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "(command line): verify-interval: Must be at least 60 seconds"
+        );
+    }
+
+    /// Problems of loading `source` from a file, without the file name
+    fn problems(source: &str, test: &str) -> Vec<String> {
+        let path =
+            std::env::temp_dir().join(format!("ifdyndnsd-{test}-{}.toml", std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let filename = path.to_str().unwrap();
+        let result = load(filename);
+        std::fs::remove_file(&path).unwrap();
+
+        let problems = match result.unwrap_err() {
+            Error::Invalid(Problems(problems)) => problems,
+            Error::Parse { problem, .. } => vec![problem],
+            e => panic!("unexpected error {:?}", e),
+        };
+        problems
+            .iter()
+            .map(|problem| {
+                assert_eq!(problem.file.as_deref(), Some(filename));
+                problem.to_string()[filename.len() + 2..].to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn problem_locations() {
+        let source = format!(
+            r#"{CONFIG}
+[[aaaa]]
+key = "missing"
+name = "other.example.net"
+zone = "example.net"
+interface = "ppp0"
+scope = "10.0.0.0/8"
+
+[[record]]
+key = "k"
+names = [
+  "mail.example.net",
+  "{{unknown}}.example.net",
+]
+zone = "example.net"
+interface = "eth0"
+"#
+        );
+        let first = CONFIG.lines().count() + 2;
+        assert_eq!(
+            problems(&source, "problem-locations"),
+            [
+                format!(
+                    "line {}: record[0].names[1]: Unknown placeholder {{unknown}}",
+                    first + 11
+                ),
+                format!("line {}: aaaa[2].key: No such key: missing", first + 1),
+                format!(
+                    "line {}: aaaa[2].scope: Scope 10.0.0.0/8 doesn't match address family IPv6",
+                    first + 5
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_error_location() {
+        assert_eq!(
+            problems("[keys.k]\nserver = 1\n", "parse-error-location"),
+            ["line 2: invalid type: integer `1`, expected IP address"]
+        );
+    }
+
+    #[test]
+    fn offline() {
+        let source = r#"
+[keys.k]
+server = "127.0.0.1"
+name = "k"
+alg = "hmac-sha256"
+secret-file = "/nonexistent/secret"
+
+[[a]]
+key = "k"
+name = "{env:IFDYNDNSD_UNSET}.example.net"
+zone = "example.net"
+interface = "ppp0"
+ttl = 60
+"#;
+        assert_eq!(
+            problems(source, "offline"),
+            [
+                "line 10: a[0].name: Environment variable IFDYNDNSD_UNSET is not set",
+                "line 6: keys.k.secret-file: Failed to read the secret-file /nonexistent/secret: No such file or directory (os error 2)",
+            ]
+        );
+
+        let path =
+            std::env::temp_dir().join(format!("ifdyndnsd-offline-{}.toml", std::process::id()));
+        std::fs::write(&path, source).unwrap();
+        let result = load_offline(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_ok());
+    }
 }
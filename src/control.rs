@@ -0,0 +1,335 @@
+//! Unix socket to inspect and steer the running daemon. Clients send
+//! one JSON request per line, like `{"command": "update", "name":
+//! "host.example.net"}`, and get one JSON response line for each.
+
+use log::{debug, error, warn};
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::task::spawn;
+
+/// Name of the socket in systemd's `RuntimeDirectory=`
+const SOCKET_NAME: &str = "control.sock";
+
+/// Location of the socket with the NixOS module
+pub const DEFAULT_PATH: &str = "/run/ifdyndnsd/control.sock";
+
+/// Longest request line that is accepted, requests are far shorter
+const MAX_REQUEST_SIZE: usize = 4096;
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// All records with their state
+    List,
+    /// Send updates now, even if DNS already has the address and
+    /// while a retry is pending. Applies to all records without
+    /// `name`.
+    Update {
+        name: Option<String>,
+    },
+    /// Query DNS now and update if it differs. Applies to all records
+    /// without `name`.
+    Verify {
+        name: Option<String>,
+    },
+    /// Stop sending updates, e.g. during maintenance of the server
+    Pause,
+    Resume,
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct Response {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Whether updates are paused
+    pub paused: bool,
+    /// The records that the request applies to
+    #[serde(default)]
+    pub records: Vec<RecordStatus>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RecordStatus {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub record_type: String,
+    pub interface: String,
+    /// Address from the interface that is to be published
+    pub address: Option<IpAddr>,
+    /// Last successfully published address
+    pub published: Option<IpAddr>,
+    /// Unix time of the last successful update
+    #[serde(rename = "last-update")]
+    pub last_update: Option<u64>,
+    /// Error of the last attempt if it failed
+    #[serde(rename = "last-error")]
+    pub last_error: Option<String>,
+    /// Unix time of the next retry
+    #[serde(rename = "next-retry")]
+    pub next_retry: Option<u64>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Cannot listen on {path}: {source}")]
+    Listen {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error("Cannot connect to {path}: {source}")]
+    Connect {
+        path: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("Invalid response: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("The daemon has closed the connection")]
+    Closed,
+}
+
+/// A request with the channel for its response
+pub type Command = (Request, oneshot::Sender<Response>);
+
+/// Default location when running with systemd's `RuntimeDirectory=`
+#[must_use]
+pub fn default_path() -> Option<String> {
+    let runtime_directory = std::env::var("RUNTIME_DIRECTORY").ok()?;
+    let runtime_directory = runtime_directory.split(':').next()?;
+    Some(format!("{runtime_directory}/{SOCKET_NAME}"))
+}
+
+/// Create the socket at `path`, replacing a stale one, and receive
+/// the requests of all clients
+///
+/// # Errors
+///
+/// Will return `Err` if the socket cannot be created.
+pub fn listen(path: &str) -> Result<Receiver<Command>, Error> {
+    let listen_error = |source| Error::Listen {
+        path: path.to_string(),
+        source,
+    };
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).map_err(listen_error)?;
+        }
+        Ok(_) => return Err(listen_error(io::ErrorKind::AlreadyExists.into())),
+        Err(_) => {}
+    }
+    let listener = bind(path).map_err(listen_error)?;
+
+    let (tx, rx) = channel(1);
+    spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    spawn(serve(stream, tx.clone()));
+                }
+                Err(e) => error!("Control socket: {e}"),
+            }
+        }
+    });
+    Ok(rx)
+}
+
+/// Create a socket at `path` that only the daemon's user and group
+/// can connect to. It is bound in a private directory and only moved
+/// to `path` once its permissions are set, as sockets cannot be
+/// created with a mode.
+fn bind(path: &str) -> io::Result<UnixListener> {
+    let dir = format!("{path}.new");
+    if fs::symlink_metadata(&dir).is_ok() {
+        fs::remove_dir_all(&dir)?;
+    }
+    fs::DirBuilder::new().mode(0o700).create(&dir)?;
+    let tmp_path = format!("{dir}/{SOCKET_NAME}");
+    let listener = UnixListener::bind(&tmp_path).and_then(|listener| {
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o660))?;
+        fs::rename(&tmp_path, path)?;
+        Ok(listener)
+    });
+    let removed = fs::remove_dir_all(&dir);
+    let listener = listener?;
+    removed?;
+    Ok(listener)
+}
+
+/// Remove the socket that has been created by [`listen`]
+pub fn remove(path: &str) {
+    if let Err(e) = fs::remove_file(path) {
+        warn!("Cannot remove {path}: {e}");
+    }
+}
+
+async fn serve(stream: UnixStream, tx: Sender<Command>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let mut limited = (&mut reader).take(MAX_REQUEST_SIZE as u64);
+        match limited.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        let too_long = line.len() == MAX_REQUEST_SIZE && !line.ends_with(b"\n");
+        let response = match serde_json::from_slice(&line) {
+            _ if too_long => Response {
+                error: Some(format!("Request longer than {MAX_REQUEST_SIZE} bytes")),
+                ..Response::default()
+            },
+            Ok(request) => {
+                debug!("Control request: {request:?}");
+                let (reply, response) = oneshot::channel();
+                if tx.send((request, reply)).await.is_err() {
+                    return;
+                }
+                match response.await {
+                    Ok(response) => response,
+                    Err(_) => return,
+                }
+            }
+            Err(e) => Response {
+                error: Some(format!("Invalid request: {e}")),
+                ..Response::default()
+            },
+        };
+        let Ok(mut buf) = serde_json::to_string(&response) else {
+            return;
+        };
+        buf.push('\n');
+        if writer.write_all(buf.as_bytes()).await.is_err() || too_long {
+            return;
+        }
+    }
+}
+
+/// Send one request to the daemon listening on `path`
+///
+/// # Errors
+///
+/// Will return `Err` if the socket cannot be connected to, or if
+/// there is no valid response.
+pub async fn send(path: &str, request: &Request) -> Result<Response, Error> {
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|source| Error::Connect {
+            path: path.to_string(),
+            source,
+        })?;
+    let (reader, mut writer) = stream.into_split();
+    let mut buf = serde_json::to_string(request)?;
+    buf.push('\n');
+    writer.write_all(buf.as_bytes()).await?;
+
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or(Error::Closed)?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests() {
+        let parse = |s| serde_json::from_str::<Request>(s).map(|request| format!("{request:?}"));
+        assert_eq!(parse(r#"{"command": "list"}"#).unwrap(), "List");
+        assert_eq!(
+            parse(r#"{"command": "update", "name": "host.example.net"}"#).unwrap(),
+            r#"Update { name: Some("host.example.net") }"#
+        );
+        assert_eq!(
+            parse(r#"{"command": "verify"}"#).unwrap(),
+            "Verify { name: None }"
+        );
+        assert!(parse(r#"{"command": "restart"}"#).is_err());
+        assert!(parse(r#"{"name": "host.example.net"}"#).is_err());
+    }
+
+    #[test]
+    fn responses() {
+        let response = Response {
+            paused: true,
+            records: vec![RecordStatus {
+                name: "host.example.net".to_string(),
+                record_type: "A".to_string(),
+                interface: "ppp0".to_string(),
+                address: Some("192.0.2.1".parse().unwrap()),
+                published: None,
+                last_update: None,
+                last_error: Some("SERVFAIL".to_string()),
+                next_retry: Some(1_700_000_000),
+            }],
+            ..Response::default()
+        };
+        let json = serde_json::to_string(&response).unwrap();
+        assert_eq!(
+            json,
+            r#"{"paused":true,"records":[{"name":"host.example.net","type":"A","interface":"ppp0","address":"192.0.2.1","published":null,"last-update":null,"last-error":"SERVFAIL","next-retry":1700000000}]}"#
+        );
+        let parsed: Response = serde_json::from_str(&json).unwrap();
+        assert_eq!(format!("{parsed:?}"), format!("{response:?}"));
+
+        let error: Response =
+            serde_json::from_str(r#"{"error":"Invalid request","paused":false}"#).unwrap();
+        assert_eq!(error.error.as_deref(), Some("Invalid request"));
+        assert!(error.records.is_empty());
+    }
+
+    #[tokio::test]
+    async fn socket() {
+        let path =
+            std::env::temp_dir().join(format!("ifdyndnsd-control-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut requests = listen(path).unwrap();
+        let mode = fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        spawn(async move {
+            while let Some((request, reply)) = requests.recv().await {
+                let paused = matches!(request, Request::Pause);
+                let _ = reply.send(Response {
+                    paused,
+                    ..Response::default()
+                });
+            }
+        });
+
+        let response = send(path, &Request::Pause).await.unwrap();
+        assert!(response.paused && response.error.is_none());
+
+        // A request that is too long gets an error and the connection
+        // is closed
+        let stream = UnixStream::connect(path).await.unwrap();
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(&[b' '; MAX_REQUEST_SIZE + 1])
+            .await
+            .unwrap();
+        let mut lines = BufReader::new(reader).lines();
+        let response: Response =
+            serde_json::from_str(&lines.next_line().await.unwrap().unwrap()).unwrap();
+        assert_eq!(
+            response.error.as_deref(),
+            Some("Request longer than 4096 bytes")
+        );
+        assert!(lines.next_line().await.unwrap().is_none());
+
+        remove(path);
+        assert!(fs::symlink_metadata(path).is_err());
+    }
+}
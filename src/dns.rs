@@ -1,53 +1,360 @@
+use crate::config;
+use crate::metrics;
+use crate::retry::ErrorClass;
 use hickory_client::client::{Client, ClientHandle};
 use hickory_client::proto::dnssec::rdata::tsig::TsigAlgorithm;
+use hickory_client::proto::dnssec::rdata::DNSSECRData;
 use hickory_client::proto::dnssec::tsig::TSigner;
+use hickory_client::proto::dnssec::DnsSecError;
 use hickory_client::proto::op::response_code::ResponseCode;
+use hickory_client::proto::op::{
+    Message, MessageFinalizer, MessageType, MessageVerifier, OpCode, Query, UpdateMessage,
+};
 use hickory_client::proto::rr::rdata::{A, AAAA};
-use hickory_client::proto::rr::{record_type::RecordType, DNSClass, Name, RData, Record};
+use hickory_client::proto::rr::{
+    record_type::RecordType, DNSClass, Name, RData, Record, RecordSet,
+};
+use hickory_client::proto::serialize::binary::{
+    BinDecodable, BinDecoder, BinEncodable, BinEncoder,
+};
 use hickory_client::proto::udp::UdpClientStream;
-use log::info;
+use hickory_client::proto::xfer::{DnsHandle, DnsResponse, FirstAnswer};
+use hickory_client::proto::ProtoError;
+use hickory_client::ClientError;
+use log::{error, info, warn};
+use std::convert::TryFrom;
 use std::net::IpAddr;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// A name could not be parsed
+    #[error("Invalid name {name:?}: {source}")]
+    Name {
+        name: String,
+        #[source]
+        source: ProtoError,
+    },
+    #[error("{name} is not in zone {zone}")]
+    NotInZone { name: String, zone: String },
+    #[error("Cannot get the key: {0}")]
+    Credentials(#[from] config::KeyError),
+    /// The TSIG key has been rejected locally, e.g. for an unsupported
+    /// algorithm
+    #[error("Invalid TSIG key: {0}")]
+    Key(#[from] DnsSecError),
+    #[error("Cannot connect: {0}")]
+    Connect(#[source] ProtoError),
+    /// No (valid) response was received
+    #[error(transparent)]
+    Network(#[from] ClientError),
+    /// The server responded with an error code, or the TSIG error
+    /// code for `NOTAUTH`
+    #[error("Response code: {0}")]
+    Response(ResponseCode),
+    /// TSIG `BADTIME`: our clock differs from the server's by more
+    /// than the fudge
+    #[error("BADTIME: the server's time is {server_time}, {skew:+}s off from ours")]
+    BadTime {
+        /// Unix time
+        server_time: u64,
+        /// Seconds that the server is ahead
+        skew: i64,
+    },
+}
+
+impl Error {
+    #[must_use]
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Error::Connect(_) | Error::Network(_) => ErrorClass::Network,
+            Error::Response(ResponseCode::ServFail) => ErrorClass::ServFail,
+            Error::Response(ResponseCode::Refused) => ErrorClass::Refused,
+            Error::BadTime { .. }
+            | Error::Response(
+                ResponseCode::NotAuth
+                | ResponseCode::BADSIG
+                | ResponseCode::BADKEY
+                | ResponseCode::BADTIME,
+            ) => ErrorClass::NotAuth,
+            Error::NotInZone { .. } | Error::Response(ResponseCode::NotZone) => ErrorClass::NotZone,
+            Error::Name { .. } | Error::Credentials(_) | Error::Key(_) | Error::Response(_) => {
+                ErrorClass::Other
+            }
+        }
+    }
+
+    fn from_response(response: &Message) -> Self {
+        match response.response_code() {
+            ResponseCode::NotAuth => match tsig_status(response) {
+                Some(TsigStatus {
+                    error: ResponseCode::BADTIME,
+                    server_time,
+                }) => Error::BadTime {
+                    server_time,
+                    skew: to_i64(server_time).saturating_sub(to_i64(unix_time())),
+                },
+                Some(TsigStatus { error, .. }) => Error::Response(error),
+                None => Error::Response(ResponseCode::NotAuth),
+            },
+            response_code => Error::Response(response_code),
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+fn to_i64(time: u64) -> i64 {
+    i64::try_from(time).unwrap_or(i64::MAX)
+}
+
+/// Fields of the TSIG record in an error response
+struct TsigStatus {
+    /// Where the server puts `BADSIG`, `BADKEY` and `BADTIME`
+    error: ResponseCode,
+    /// Unix time of the server, from the other data that comes with
+    /// `BADTIME`, or the time signed
+    server_time: u64,
+}
+
+fn tsig_status(response: &Message) -> Option<TsigStatus> {
+    let tsig = response
+        .signature()
+        .iter()
+        .find_map(|record| match record.data() {
+            RData::DNSSEC(DNSSECRData::TSIG(tsig)) => Some(tsig),
+            _ => None,
+        })?;
+    let mut buf = Vec::new();
+    tsig.emit(&mut BinEncoder::new(&mut buf)).ok()?;
+
+    let mut decoder = BinDecoder::new(&buf);
+    let read_time = |decoder: &mut BinDecoder<'_>| -> Option<u64> {
+        let high = decoder.read_u16().ok()?.unverified();
+        let low = decoder.read_u32().ok()?.unverified();
+        Some(u64::from(high) << 32 | u64::from(low))
+    };
+    Name::read(&mut decoder).ok()?;
+    let time_signed = read_time(&mut decoder)?;
+    // fudge
+    decoder.read_u16().ok()?;
+    let mac_size = decoder.read_u16().ok()?.unverified();
+    decoder.read_slice(mac_size.into()).ok()?;
+    // original id
+    decoder.read_u16().ok()?;
+    let error = decoder.read_u16().ok()?.unverified();
+    let other_size = decoder.read_u16().ok()?.unverified();
+    let server_time = if other_size == 6 {
+        read_time(&mut decoder)?
+    } else {
+        time_signed
+    };
+    Some(TsigStatus {
+        error: error.into(),
+        server_time,
+    })
+}
+
+/// `TSigner` rejects any response that it cannot verify. Servers
+/// cannot sign responses with a key they don't recognize, so this
+/// wrapper passes those error responses on for their response code
+/// to be reported. Responses without an error are still required to
+/// be properly signed.
+///
+/// Messages are signed with the current time corrected by `skew`.
+struct Signer {
+    signer: TSigner,
+    /// Seconds to add to the local time
+    skew: Arc<AtomicI64>,
+}
+
+impl MessageFinalizer for Signer {
+    fn finalize_message(
+        &self,
+        message: &Message,
+        current_time: u32,
+    ) -> Result<(Vec<Record>, Option<MessageVerifier>), ProtoError> {
+        let skew = self.skew.load(Ordering::Relaxed);
+        let current_time = u32::try_from(i64::from(current_time) + skew).unwrap_or(current_time);
+        let (records, verifier) = self.signer.finalize_message(message, current_time)?;
+        let verifier = verifier.map(|mut verifier| -> MessageVerifier {
+            Box::new(move |response: &[u8]| {
+                verifier(response).or_else(|e| match DnsResponse::from_buffer(response.to_vec()) {
+                    Ok(response) if response.response_code() != ResponseCode::NoError => {
+                        warn!("Unverified error response: {e}");
+                        Ok(response)
+                    }
+                    _ => Err(e),
+                })
+            })
+        });
+        Ok((records, verifier))
+    }
+}
 
 pub struct Server {
+    addr: IpAddr,
+    key: config::TsigKey,
+    credentials: config::Credentials,
+    /// Index of the secret that is used
+    current: usize,
+    /// Correction of the signing time, see `TsigKey::correct_skew`
+    skew: Arc<AtomicI64>,
     client: Client,
+    /// Only log updates instead of sending them
+    dry_run: bool,
+}
+
+/// Secrets and clock corrections that have been tried for one request
+#[derive(Default)]
+struct Retries {
+    rejected: usize,
+    /// Whether the secrets have been read again
+    reloaded: bool,
+    /// Whether the signing time has been corrected after `BADTIME`
+    corrected: bool,
 }
 
 impl Server {
-    /// # Panics
+    /// # Errors
     ///
-    /// Will panic if
+    /// Will return `Err` if
     ///
-    /// - Configuration parameter `key.alg` is non-ascii or doesn't match a valid algorithm.
-    /// - Configuration parameter `key.name` could not be parsed into a UTF-8 string.
+    /// - The key cannot be read, see `TsigKey::credentials()`.
+    /// - The key's algorithm is non-ascii or doesn't match a valid algorithm.
+    /// - The key's name could not be parsed into a UTF-8 string.
     /// - Establishing a connection to the DNS endpoint failed.
     ///
-    pub async fn new(addr: IpAddr, key: &crate::config::TsigKey) -> Self {
-        let alg = TsigAlgorithm::from_name(Name::from_str(&key.alg).unwrap());
-        let signer = TSigner::new(
-            key.get_secret(),
-            alg,
-            Name::from_str(&key.name).unwrap(),
-            300, // Standard value according to RFC 2845, Sec. 6
-        )
-        .unwrap();
-
-        let stream = UdpClientStream::builder(
-            (addr, 53).into(),
-            hickory_client::proto::runtime::TokioRuntimeProvider::default(),
-        )
-        .with_timeout(Some(Duration::from_secs(3)))
-        .with_signer(Some(Arc::new(signer)))
-        .build();
-        let (mut client, bg) = Client::connect(stream).await.unwrap();
-        client.disable_edns();
-
-        tokio::spawn(bg);
-
-        Server { client }
+    /// Keys that have been validated by `config::load()` only fail
+    /// when their secret file has become unreadable, or when secrets
+    /// from the environment, credentials or commands are unavailable.
+    ///
+    pub async fn new(addr: IpAddr, key: &config::TsigKey) -> Result<Self, Error> {
+        let credentials = key.credentials()?;
+        let skew = Arc::new(AtomicI64::new(0));
+        let client = connect(addr, key, &credentials, 0, skew.clone()).await?;
+        Ok(Server {
+            addr,
+            key: key.clone(),
+            credentials,
+            current: 0,
+            skew,
+            client,
+            dry_run: false,
+        })
+    }
+
+    /// Switch to the next secret after the server has rejected the
+    /// current one. Once all have been rejected, the secrets are read
+    /// again in case they have been rotated.
+    ///
+    /// Returns whether there is another secret to try.
+    async fn rotate(&mut self, retries: &mut Retries) -> bool {
+        let key_name = self.credentials.name.clone();
+        retries.rejected += 1;
+        if retries.rejected < self.credentials.secrets.len() {
+            let rejected = self.current;
+            self.current = (self.current + 1) % self.credentials.secrets.len();
+            warn!(
+                "Key {key_name}: secret {} has been rejected, trying secret {}",
+                rejected + 1,
+                self.current + 1
+            );
+        } else if !retries.reloaded {
+            retries.reloaded = true;
+            match self.key.credentials() {
+                Ok(credentials) if credentials != self.credentials => {
+                    warn!(
+                        "Key {key_name}: all secrets have been rejected, trying the changed ones"
+                    );
+                    self.credentials = credentials;
+                    self.current = 0;
+                    retries.rejected = 0;
+                }
+                Ok(_) => return false,
+                Err(e) => {
+                    error!("Key {key_name}: cannot read the secrets again: {e}");
+                    return false;
+                }
+            }
+        } else {
+            return false;
+        }
+
+        let client = connect(
+            self.addr,
+            &self.key,
+            &self.credentials,
+            self.current,
+            self.skew.clone(),
+        );
+        match client.await {
+            Ok(client) => {
+                self.client = client;
+                true
+            }
+            Err(e) => {
+                error!("Key {key_name}: {e}");
+                false
+            }
+        }
     }
+
+    /// Whether to send a request again after its `result`
+    async fn retry_rejected<T>(
+        &mut self,
+        result: &Result<T, Error>,
+        retries: &mut Retries,
+    ) -> bool {
+        match result {
+            Err(Error::Response(ResponseCode::BADKEY | ResponseCode::BADSIG)) => {
+                return self.rotate(retries).await;
+            }
+            Err(Error::BadTime { skew, .. }) => {
+                if self.key.correct_skew == Some(true) && !retries.corrected {
+                    retries.corrected = true;
+                    warn!(
+                        "Key {}: the server's clock is {skew:+}s off from ours, correcting the signing time",
+                        self.credentials.name
+                    );
+                    self.skew.store(*skew, Ordering::Relaxed);
+                    return true;
+                }
+                warn!(
+                    "Key {}: the server's clock is {skew:+}s off from ours, fix the clock or set correct-skew",
+                    self.credentials.name
+                );
+                return false;
+            }
+            _ => {}
+        }
+        if retries.rejected > 0 || retries.reloaded {
+            info!(
+                "Key {}: using secret {} from now on",
+                self.credentials.name,
+                self.current + 1
+            );
+        }
+        false
+    }
+
+    /// Only log the updates that would be sent. Queries are still sent.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.dry_run = dry_run;
+    }
+
+    #[must_use]
+    pub fn is_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
     /// # Errors
     ///
     /// Will return `Err` if
@@ -55,22 +362,25 @@ impl Server {
     /// - `name` could not be parsed into a UTF-8 string.
     /// - The DNS query failed.
     ///
+    /// Returns the addresses with their TTL.
     pub async fn query(
         &mut self,
         name: &str,
         record_type: RecordType,
-    ) -> Result<Vec<IpAddr>, String> {
-        let query = self
-            .client
-            .query(Name::from_str(name)?, DNSClass::IN, record_type);
-        let response = query.await.map_err(|e| format!("{e}"))?;
+    ) -> Result<Vec<(IpAddr, u32)>, Error> {
+        let name = parse_name(name)?;
+        let query = self.client.query(name, DNSClass::IN, record_type);
+        let started = Instant::now();
+        let response = query.await;
+        metrics::query_duration(started.elapsed());
+        let response = response?;
 
         let result = response
             .answers()
             .iter()
             .filter_map(|answer| match answer.data() {
-                RData::A(addr) => Some(addr.0.into()),
-                RData::AAAA(addr) => Some(addr.0.into()),
+                RData::A(addr) => Some((addr.0.into(), answer.ttl())),
+                RData::AAAA(addr) => Some((addr.0.into(), answer.ttl())),
                 _ => None,
             })
             .collect::<Vec<_>>();
@@ -91,32 +401,383 @@ impl Server {
         addr: IpAddr,
         zone: Option<&str>,
         ttl: u32,
-    ) -> Result<(), String> {
+    ) -> Result<(), Error> {
         let rdata = match addr {
             IpAddr::V4(addr) => RData::A(A(addr)),
             IpAddr::V6(addr) => RData::AAAA(AAAA(addr)),
         };
-        let name = Name::from_str(name)?;
+        self.replace(name, zone, ttl, vec![rdata]).await
+    }
 
-        // This is introduced to deal with legacy configurations without a `zone` set.
-        let zone = match zone {
-            Some(zone) => Name::from_str(zone)?,
-            None => name.base_name(),
-        };
-        let rec = Record::from_rdata(name.clone(), ttl, rdata);
-        let query = self.client.delete_rrset(rec.clone(), zone.clone());
-        let response = query.await.map_err(|e| format!("{e}"))?;
+    /// Replace the resource record set at `name` with `rdatas`, which
+    /// must all have the same record type
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` in case
+    ///
+    /// - `name` or `zone` cannot be parsed.
+    /// - deletion of resource record set failed.
+    /// - appending the new records failed.
+    ///
+    /// # Panics
+    ///
+    /// Will panic if `rdatas` is empty.
+    pub async fn replace(
+        &mut self,
+        name: &str,
+        zone: Option<&str>,
+        ttl: u32,
+        rdatas: Vec<RData>,
+    ) -> Result<(), Error> {
+        let (name, zone) = parse_names(name, zone)?;
+        let record_type = rdatas[0].record_type();
+        let mut rrset = RecordSet::with_ttl(name.clone(), record_type, ttl);
+        for rdata in rdatas {
+            rrset.add_rdata(rdata);
+        }
+        if self.dry_run {
+            info!(
+                "Dry run: would delete {name} {record_type} and add {} in zone {zone}",
+                format_records(&rrset)
+            );
+            return Ok(());
+        }
+        let mut retries = Retries::default();
+        loop {
+            let result = self.replace_rrset(&rrset, &zone).await;
+            if !self.retry_rejected(&result, &mut retries).await {
+                return result;
+            }
+        }
+    }
+
+    async fn replace_rrset(&mut self, rrset: &RecordSet, zone: &Name) -> Result<(), Error> {
+        let rec = Record::update0(rrset.name().clone(), 0, rrset.record_type());
+        let query = self.client.delete_rrset(rec, zone.clone());
+        let response = query.await?;
 
         if response.response_code() != ResponseCode::NoError {
-            return Err(format!("Response code: {}", response.response_code()));
+            return Err(Error::from_response(&response));
         }
-        let query = self.client.append(rec, zone, false);
-        info!("DNS update: {name} {addr}");
-        let response = query.await.map_err(|e| format!("{e}"))?;
+        let query = self.client.append(rrset.clone(), zone.clone(), false);
+        info!("DNS update: {}", format_records(rrset));
+        let response = query.await?;
 
         if response.response_code() != ResponseCode::NoError {
-            return Err(format!("Response code: {}", response.response_code()));
+            return Err(Error::from_response(&response));
         }
         Ok(())
     }
+    /// Delete the whole resource record set of `record_type` at `name`
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` in case
+    ///
+    /// - `name` can not be parsed into a UTF-8 string.
+    /// - deletion of resource record set failed.
+    ///
+    pub async fn delete(
+        &mut self,
+        name: &str,
+        record_type: RecordType,
+        zone: Option<&str>,
+    ) -> Result<(), Error> {
+        let (name, zone) = parse_names(name, zone)?;
+        if self.dry_run {
+            info!("Dry run: would delete {name} {record_type} in zone {zone}");
+            return Ok(());
+        }
+        let rec = Record::update0(name.clone(), 0, record_type);
+        let mut retries = Retries::default();
+        loop {
+            let query = self.client.delete_rrset(rec.clone(), zone.clone());
+            info!("DNS delete: {name} {record_type}");
+            let result = match query.await {
+                Ok(response) if response.response_code() != ResponseCode::NoError => {
+                    Err(Error::from_response(&response))
+                }
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+            if !self.retry_rejected(&result, &mut retries).await {
+                return result;
+            }
+        }
+    }
+
+    /// Delete the records with `rdatas` at `name`, and keep others of
+    /// the same type. `rdatas` must all have the same record type.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` in case
+    ///
+    /// - `name` or `zone` cannot be parsed.
+    /// - deletion of the records failed.
+    ///
+    pub async fn delete_records(
+        &mut self,
+        name: &str,
+        zone: Option<&str>,
+        rdatas: Vec<RData>,
+    ) -> Result<(), Error> {
+        let (name, zone) = parse_names(name, zone)?;
+        let mut records = rdatas
+            .into_iter()
+            .map(|rdata| Record::from_rdata(name.clone(), 0, rdata))
+            .peekable();
+        let Some(first) = records.peek() else {
+            return Ok(());
+        };
+        let mut rrset = RecordSet::new(name.clone(), first.record_type(), 0);
+        for record in records {
+            rrset.insert(record, 0);
+        }
+        if self.dry_run {
+            info!(
+                "Dry run: would delete {} in zone {zone}",
+                format_records(&rrset)
+            );
+            return Ok(());
+        }
+        let mut retries = Retries::default();
+        loop {
+            let query = self.client.delete_by_rdata(rrset.clone(), zone.clone());
+            info!("DNS delete: {}", format_records(&rrset));
+            let result = match query.await {
+                Ok(response) if response.response_code() != ResponseCode::NoError => {
+                    Err(Error::from_response(&response))
+                }
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+            if !self.retry_rejected(&result, &mut retries).await {
+                return result;
+            }
+        }
+    }
+
+    /// Send a signed query for the SOA record of the zone of `name`.
+    /// A valid response shows that the server is reachable and
+    /// accepts the key.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `name` or `zone` cannot be parsed, or if
+    /// there is no response without an error.
+    pub async fn query_soa(&mut self, name: &str, zone: Option<&str>) -> Result<(), Error> {
+        let (_, zone) = parse_names(name, zone)?;
+        let mut retries = Retries::default();
+        loop {
+            let query = self
+                .client
+                .query(zone.clone(), DNSClass::IN, RecordType::SOA);
+            let result = match query.await {
+                Ok(response) if response.response_code() != ResponseCode::NoError => {
+                    Err(Error::from_response(&response))
+                }
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.into()),
+            };
+            if !self.retry_rejected(&result, &mut retries).await {
+                return result;
+            }
+        }
+    }
+
+    /// Send an update of the `record_type` records at `name` that
+    /// changes nothing: it only deletes the unspecified address, which
+    /// is never published.
+    ///
+    /// Prerequisites are checked before the update policy (RFC 2136,
+    /// 3.2 and 3.3), so there are none, and the server accepts the
+    /// update only if the key is authorized for `name`.
+    ///
+    /// # Errors
+    ///
+    /// Will return `Err` if `name` or `zone` cannot be parsed, or if
+    /// the server refuses the update.
+    pub async fn check_update(
+        &mut self,
+        name: &str,
+        zone: Option<&str>,
+        record_type: RecordType,
+    ) -> Result<(), Error> {
+        let (name, zone) = parse_names(name, zone)?;
+        let unspecified = match record_type {
+            RecordType::A => RData::A(A::new(0, 0, 0, 0)),
+            _ => RData::AAAA(AAAA::new(0, 0, 0, 0, 0, 0, 0, 0)),
+        };
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Query)
+            .set_op_code(OpCode::Update)
+            .set_recursion_desired(false);
+        let mut query = Query::new();
+        query
+            .set_name(zone)
+            .set_query_class(DNSClass::IN)
+            .set_query_type(RecordType::SOA);
+        message.add_zone(query);
+        let mut update = Record::from_rdata(name, 0, unspecified);
+        update.set_dns_class(DNSClass::NONE);
+        message.add_update(update);
+
+        let mut retries = Retries::default();
+        loop {
+            let response = self.client.send(message.clone()).first_answer().await;
+            let result = match response {
+                Ok(response) if response.response_code() != ResponseCode::NoError => {
+                    Err(Error::from_response(&response))
+                }
+                Ok(_) => Ok(()),
+                Err(e) => Err(ClientError::from(e).into()),
+            };
+            if !self.retry_rejected(&result, &mut retries).await {
+                return result;
+            }
+        }
+    }
+}
+
+/// Like `name ttl type rdata, ...`
+fn format_records(rrset: &RecordSet) -> String {
+    rrset
+        .records_without_rrsigs()
+        .map(|rec| {
+            format!(
+                "{} {} {} {}",
+                rec.name(),
+                rec.ttl(),
+                rec.record_type(),
+                rec.data()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Set up a client that signs with the secret at `index`
+async fn connect(
+    addr: IpAddr,
+    key: &config::TsigKey,
+    credentials: &config::Credentials,
+    index: usize,
+    skew: Arc<AtomicI64>,
+) -> Result<Client, Error> {
+    let alg = TsigAlgorithm::from_name(parse_name(&credentials.alg)?);
+    let signer = TSigner::new(
+        credentials.secrets[index].expose().to_vec(),
+        alg,
+        parse_name(&credentials.name)?,
+        key.fudge.unwrap_or(config::DEFAULT_FUDGE),
+    )?;
+
+    let stream = UdpClientStream::builder(
+        (addr, 53).into(),
+        hickory_client::proto::runtime::TokioRuntimeProvider::default(),
+    )
+    .with_timeout(Some(Duration::from_secs(3)))
+    .with_signer(Some(Arc::new(Signer { signer, skew })))
+    .build();
+    let (mut client, bg) = Client::connect(stream).await.map_err(Error::Connect)?;
+    client.disable_edns();
+
+    tokio::spawn(bg);
+
+    Ok(client)
+}
+
+fn parse_name(name: &str) -> Result<Name, Error> {
+    Name::from_str(name).map_err(|source| Error::Name {
+        name: name.to_string(),
+        source,
+    })
+}
+
+fn parse_names(name: &str, zone: Option<&str>) -> Result<(Name, Name), Error> {
+    let name = parse_name(name)?;
+
+    // This is introduced to deal with legacy configurations without a `zone` set.
+    let zone = match zone {
+        Some(zone) => parse_name(zone)?,
+        None => name.base_name(),
+    };
+    if !zone.zone_of(&name) {
+        return Err(Error::NotInZone {
+            name: name.to_string(),
+            zone: zone.to_string(),
+        });
+    }
+    Ok((name, zone))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_client::proto::dnssec::rdata::tsig::TSIG;
+
+    const TIME_SIGNED: u64 = 1_700_000_000;
+
+    /// A `NOTAUTH` response as it arrives, with the TSIG `error` and
+    /// `other` data if any
+    fn response(tsig: Option<(ResponseCode, Vec<u8>)>) -> Message {
+        let mut message = Message::error_msg(1, OpCode::Update, ResponseCode::NotAuth);
+        if let Some((error, other)) = tsig {
+            let tsig = TSIG::new(
+                TsigAlgorithm::HmacSha256,
+                TIME_SIGNED,
+                300,
+                vec![],
+                1,
+                error.into(),
+                other,
+            );
+            message.add_tsig(Record::from_rdata(
+                Name::from_str("k.").unwrap(),
+                0,
+                RData::DNSSEC(DNSSECRData::TSIG(tsig)),
+            ));
+        }
+        Message::from_vec(&message.to_vec().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn bad_time() {
+        // Needs the high 16 bits of the 48 bit time
+        let server_time = TIME_SIGNED + 0x1_0000_0300;
+        let other = server_time.to_be_bytes()[2..].to_vec();
+        let e = Error::from_response(&response(Some((ResponseCode::BADTIME, other))));
+        assert_eq!(e.class(), ErrorClass::NotAuth);
+        match e {
+            Error::BadTime {
+                server_time: time, ..
+            } => assert_eq!(time, server_time),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn bad_time_without_server_time() {
+        match Error::from_response(&response(Some((ResponseCode::BADTIME, vec![])))) {
+            Error::BadTime { server_time, .. } => assert_eq!(server_time, TIME_SIGNED),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn tsig_errors() {
+        for error in [ResponseCode::BADSIG, ResponseCode::BADKEY] {
+            match Error::from_response(&response(Some((error, vec![])))) {
+                Error::Response(code) => assert_eq!(code, error),
+                e => panic!("unexpected error {:?}", e),
+            }
+        }
+        match Error::from_response(&response(None)) {
+            Error::Response(code) => assert_eq!(code, ResponseCode::NotAuth),
+            e => panic!("unexpected error {:?}", e),
+        }
+    }
 }
@@ -1,3 +1,4 @@
+use crate::metrics;
 use futures::{
     future::ok,
     stream::{StreamExt, TryStreamExt},
@@ -6,9 +7,11 @@ use log::{debug, error, trace};
 use netlink_packet_core::NetlinkPayload;
 use std::collections::HashMap;
 use std::net::IpAddr;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
 
 use netlink_packet_route::{
-    address::{AddressAttribute, AddressHeaderFlags, AddressMessage},
+    address::{AddressAttribute, AddressFlags, AddressHeaderFlags, AddressMessage, AddressScope},
     link::{LinkAttribute, LinkMessage},
     RouteNetlinkMessage,
 };
@@ -16,31 +19,160 @@ use netlink_packet_route::{
 use netlink_sys::{AsyncSocket, SocketAddr};
 use rtnetlink::{
     constants::{RTMGRP_IPV4_IFADDR, RTMGRP_IPV6_IFADDR, RTMGRP_LINK},
-    new_connection,
+    new_connection, Handle,
 };
 use tokio::{
     sync::mpsc::{channel, Receiver, Sender},
     task::spawn,
+    time::interval,
 };
 
+/// How often the listener shows that it is still running
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Last time the listener has shown that it is running, see `is_alive()`
+static HEARTBEAT: Mutex<Option<Instant>> = Mutex::new(None);
+
+#[derive(Debug)]
+pub enum Event {
+    /// An interface has got an address
+    Address(String, IpAddr),
+    /// An address has been removed from an interface
+    AddressRemoved(String, IpAddr),
+    /// All addresses present at startup have been sent, with the names
+    /// of all interfaces at that time
+    InitialDumpDone(Vec<String>),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Cannot open netlink socket: {0}")]
+    Socket(#[from] std::io::Error),
+    #[error("Netlink request failed: {0}")]
+    Request(#[from] rtnetlink::Error),
+}
+
+/// An address as reported by netlink, including those that are never
+/// published
+#[derive(Clone, Debug)]
+pub struct Address {
+    pub interface: String,
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+    /// Like `global` or `link`
+    pub scope: String,
+    /// Like `permanent` or `deprecated`, as shown by `ip address`
+    pub flags: Vec<&'static str>,
+    /// Temporary IPv6 addresses (privacy extensions) and secondary
+    /// IPv4 addresses are ignored
+    pub ignored: bool,
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{} scope {}", self.addr, self.prefix_len, self.scope)?;
+        for flag in &self.flags {
+            write!(f, " {flag}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Any change of addresses, see `watch()`
+#[derive(Debug)]
+pub enum Change {
+    Added(Address),
+    Removed(Address),
+    /// All addresses present at startup have been sent, with the names
+    /// of all interfaces at that time
+    InitialDumpDone(Vec<String>),
+}
+
+const FLAG_NAMES: [(AddressFlags, &str, &str); 12] = [
+    (AddressFlags::Secondary, "secondary", "temporary"),
+    (AddressFlags::Nodad, "nodad", "nodad"),
+    (AddressFlags::Optimistic, "optimistic", "optimistic"),
+    (AddressFlags::Dadfailed, "dadfailed", "dadfailed"),
+    (AddressFlags::Homeaddress, "home", "home"),
+    (AddressFlags::Deprecated, "deprecated", "deprecated"),
+    (AddressFlags::Tentative, "tentative", "tentative"),
+    (AddressFlags::Permanent, "permanent", "permanent"),
+    (AddressFlags::Managetempaddr, "mngtmpaddr", "mngtmpaddr"),
+    (
+        AddressFlags::Noprefixroute,
+        "noprefixroute",
+        "noprefixroute",
+    ),
+    (AddressFlags::Mcautojoin, "autojoin", "autojoin"),
+    (
+        AddressFlags::StablePrivacy,
+        "stable-privacy",
+        "stable-privacy",
+    ),
+];
+
+/// Listen for addresses that can be published
+#[must_use]
+pub fn start() -> Receiver<Event> {
+    listen(|change| match change {
+        Change::Added(address) if !address.ignored => {
+            Some(Event::Address(address.interface, address.addr))
+        }
+        Change::Removed(address) if !address.ignored => {
+            Some(Event::AddressRemoved(address.interface, address.addr))
+        }
+        Change::Added(_) | Change::Removed(_) => None,
+        Change::InitialDumpDone(interfaces) => Some(Event::InitialDumpDone(interfaces)),
+    })
+}
+
+/// Listen for all changes of addresses, including ignored ones
+#[must_use]
+pub fn watch() -> Receiver<Change> {
+    listen(Some)
+}
+
+fn beat() {
+    *HEARTBEAT.lock().unwrap_or_else(PoisonError::into_inner) = Some(Instant::now());
+}
+
+/// Whether the listener is still running: it has dumped the addresses
+/// and receives messages, or waits for them, but isn't stuck. A missed
+/// heartbeat or two are tolerated.
 #[must_use]
-pub fn start() -> Receiver<(String, IpAddr)> {
+pub fn is_alive() -> bool {
+    HEARTBEAT
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .is_some_and(|heartbeat| heartbeat.elapsed() < HEARTBEAT_INTERVAL * 3)
+}
+
+/// Send `change` if `event` turns it into an event
+async fn forward<T>(tx: &Sender<T>, event: fn(Change) -> Option<T>, change: Change) {
+    if let Some(event) = event(change) {
+        tx.send(event).await.unwrap();
+    }
+}
+
+fn listen<T: Send + 'static>(event: fn(Change) -> Option<T>) -> Receiver<T> {
     let (mut tx, rx) = channel(1);
 
+    beat();
     spawn(async move {
         loop {
-            match run(&mut tx).await {
+            match run(&mut tx, event).await {
                 Ok(()) => error!("nfnetlink: restarting listener"),
                 Err(e) => error!("nfnetlink error: {e}"),
             }
+            metrics::netlink_restart();
         }
     });
     rx
 }
 
-async fn run(tx: &mut Sender<(String, IpAddr)>) -> Result<(), String> {
+async fn run<T>(tx: &mut Sender<T>, event: fn(Change) -> Option<T>) -> Result<(), Error> {
     // Open the netlink socket
-    let (mut connection, handle, mut messages) = new_connection().map_err(|e| format!("{e}"))?;
+    let (mut connection, handle, mut messages) = new_connection()?;
 
     // These flags specify what kinds of broadcast messages we want to listen for.
     let mgroup_flags = RTMGRP_LINK | RTMGRP_IPV4_IFADDR | RTMGRP_IPV6_IFADDR;
@@ -48,50 +180,36 @@ async fn run(tx: &mut Sender<(String, IpAddr)>) -> Result<(), String> {
     // A netlink socket address is created with said flags.
     let addr = SocketAddr::new(0, mgroup_flags);
     // Said address is bound so new conenctions and thus new message broadcasts can be received.
-    connection
-        .socket_mut()
-        .socket_mut()
-        .bind(&addr)
-        .expect("failed to bind");
+    connection.socket_mut().socket_mut().bind(&addr)?;
     tokio::spawn(connection);
 
-    let mut interface_names = HashMap::new();
-    let links = handle.link().get().execute();
-    links
-        .try_for_each(|m| {
-            let index = m.header.index;
-            if let Some(name) = link_message_name(&m) {
-                interface_names.insert(index, name.clone());
-            }
-            ok(())
-        })
-        .await
-        .map_err(|e| format!("{e:x?}"))?;
+    beat();
+    let (mut interface_names, initial) = dump_addresses(&handle).await?;
 
-    let mut initial = vec![];
-    handle
-        .address()
-        .get()
-        .execute()
-        .try_for_each(|m| {
-            if let Some(name) = interface_names.get(&m.header.index) {
-                if let Some(addr) = message_local_addr(&m) {
-                    initial.push((name.clone(), addr));
-                }
-            }
-
-            ok(())
-        })
-        .await
-        .map_err(|e| format!("{e:x?}"))?;
-
-    for value in initial {
-        debug!("interface {}: initial address {:?}", value.0, value.1);
-        tx.send(value).await.unwrap();
+    for address in initial {
+        debug!(
+            "interface {}: initial address {:?}",
+            address.interface, address.addr
+        );
+        forward(tx, event, Change::Added(address)).await;
     }
+    let interfaces = interface_names.values().cloned().collect();
+    forward(tx, event, Change::InitialDumpDone(interfaces)).await;
 
-    while let Some((message, _)) = messages.next().await {
+    let mut heartbeat = interval(HEARTBEAT_INTERVAL);
+    loop {
+        let message = tokio::select! {
+            message = messages.next() => message,
+            _ = heartbeat.tick() => {
+                beat();
+                continue;
+            }
+        };
+        let Some((message, _)) = message else {
+            break;
+        };
         trace!("netlink message: {message:?}");
+        metrics::netlink_event();
         match message.payload {
             NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewLink(m)) => {
                 let index = m.header.index;
@@ -104,13 +222,20 @@ async fn run(tx: &mut Sender<(String, IpAddr)>) -> Result<(), String> {
             }
             NetlinkPayload::InnerMessage(RouteNetlinkMessage::NewAddress(m)) => {
                 if let Some(name) = interface_names.get(&m.header.index) {
-                    if let Some(addr) = message_local_addr(&m) {
-                        tx.send((name.clone(), addr)).await.unwrap();
+                    if let Some(address) = message_address(name, &m) {
+                        forward(tx, event, Change::Added(address)).await;
                     }
                 } else {
                     error!("No such link with index={}", m.header.index);
                 }
             }
+            NetlinkPayload::InnerMessage(RouteNetlinkMessage::DelAddress(m)) => {
+                if let Some(name) = interface_names.get(&m.header.index) {
+                    if let Some(address) = message_address(name, &m) {
+                        forward(tx, event, Change::Removed(address)).await;
+                    }
+                }
+            }
             _ => {
                 // println!("Other - {:x?}", message.payload);
             }
@@ -119,13 +244,109 @@ async fn run(tx: &mut Sender<(String, IpAddr)>) -> Result<(), String> {
     Ok(())
 }
 
-fn message_local_addr(m: &AddressMessage) -> Option<IpAddr> {
-    // Ignore IPv6 temp_addrs
-    let is_temporary = m.header.flags.contains(AddressHeaderFlags::Secondary);
-    if is_temporary {
-        return None;
-    }
+/// Current addresses of all interfaces
+///
+/// # Errors
+///
+/// Will return `Err` if netlink cannot be queried.
+pub async fn dump() -> Result<Vec<(String, IpAddr)>, Error> {
+    let addrs = dump_all()
+        .await?
+        .into_iter()
+        .filter(|address| !address.ignored)
+        .map(|address| (address.interface, address.addr))
+        .collect();
+    Ok(addrs)
+}
 
+/// Current addresses of all interfaces, including ignored ones
+///
+/// # Errors
+///
+/// Will return `Err` if netlink cannot be queried.
+pub async fn dump_all() -> Result<Vec<Address>, Error> {
+    let (connection, handle, _) = new_connection()?;
+    tokio::spawn(connection);
+
+    let (_, addrs) = dump_addresses(&handle).await?;
+    Ok(addrs)
+}
+
+/// Get the names of all interfaces and their current addresses
+async fn dump_addresses(handle: &Handle) -> Result<(HashMap<u32, String>, Vec<Address>), Error> {
+    let mut interface_names = HashMap::new();
+    let links = handle.link().get().execute();
+    links
+        .try_for_each(|m| {
+            let index = m.header.index;
+            if let Some(name) = link_message_name(&m) {
+                interface_names.insert(index, name.clone());
+            }
+            ok(())
+        })
+        .await?;
+
+    let mut initial = vec![];
+    handle
+        .address()
+        .get()
+        .execute()
+        .try_for_each(|m| {
+            if let Some(name) = interface_names.get(&m.header.index) {
+                if let Some(address) = message_address(name, &m) {
+                    initial.push(address);
+                }
+            }
+
+            ok(())
+        })
+        .await?;
+
+    Ok((interface_names, initial))
+}
+
+fn message_address(interface: &str, m: &AddressMessage) -> Option<Address> {
+    let addr = message_local_addr(m)?;
+    // IFA_FLAGS has all flags, the header only the lower 8 bits
+    let flags = m
+        .attributes
+        .iter()
+        .find_map(|a| {
+            if let AddressAttribute::Flags(flags) = a {
+                Some(*flags)
+            } else {
+                None
+            }
+        })
+        .unwrap_or_else(|| AddressFlags::from_bits_retain(m.header.flags.bits().into()));
+    let flag_names = FLAG_NAMES
+        .iter()
+        .filter(|(flag, _, _)| flags.contains(*flag))
+        .map(|(_, ipv4_name, ipv6_name)| match addr {
+            IpAddr::V4(_) => *ipv4_name,
+            IpAddr::V6(_) => *ipv6_name,
+        })
+        .collect();
+    let scope = match m.header.scope {
+        AddressScope::Universe => "global".to_string(),
+        AddressScope::Site => "site".to_string(),
+        AddressScope::Link => "link".to_string(),
+        AddressScope::Host => "host".to_string(),
+        AddressScope::Nowhere => "nowhere".to_string(),
+        scope => u8::from(scope).to_string(),
+    };
+    Some(Address {
+        interface: interface.to_string(),
+        addr,
+        prefix_len: m.header.prefix_len,
+        scope,
+        flags: flag_names,
+        // Ignore IPv6 temp_addrs
+        ignored: m.header.flags.contains(AddressHeaderFlags::Secondary),
+    })
+}
+
+fn message_local_addr(m: &AddressMessage) -> Option<IpAddr> {
     // Get the local address for a pointopoint link
     if let Some(local) = m.attributes.iter().find_map(|a| {
         if let AddressAttribute::Local(addr) = a {
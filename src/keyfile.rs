@@ -0,0 +1,386 @@
+//! Parser for BIND key files as written by `ddns-confgen` and
+//! `tsig-keygen`:
+//!
+//! ```text
+//! key "ddns-key.example.com" {
+//!         algorithm hmac-sha256;
+//!         secret "dG9wc2VjcmV0Cg==";
+//! };
+//! ```
+//!
+//! Statements other than `key` are skipped so that whole `named.conf`
+//! snippets can be used.
+
+use crate::secret::Secret;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::iter::Peekable;
+use std::str::Chars;
+
+#[derive(Debug, PartialEq)]
+pub struct Key {
+    pub name: String,
+    pub algorithm: String,
+    /// Base64 encoded
+    pub secret: Secret,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("line {line}: {message}")]
+    Syntax { line: usize, message: String },
+}
+
+fn syntax(line: usize, message: String) -> Error {
+    Error::Syntax { line, message }
+}
+
+/// Whether two key names are the same, ignoring case and a trailing dot
+#[must_use]
+pub fn same_name(a: &str, b: &str) -> bool {
+    a.trim_end_matches('.')
+        .eq_ignore_ascii_case(b.trim_end_matches('.'))
+}
+
+/// # Errors
+///
+/// Will return `Err` if `filename` cannot be read or parsed.
+pub fn load(filename: &str) -> Result<Vec<Key>, Error> {
+    let mut f = File::open(filename)?;
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+    // Wiped when dropped
+    let buf = Secret::from(buf);
+    let source = std::str::from_utf8(buf.expose())
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    parse(source)
+}
+
+/// # Errors
+///
+/// Will return `Err` on syntax errors, or if a key misses its
+/// algorithm or secret.
+pub fn parse(source: &str) -> Result<Vec<Key>, Error> {
+    let mut parser = Parser {
+        tokens: tokenize(source)?.into_iter(),
+        line: 1,
+    };
+    let mut keys = vec![];
+    while let Some((line, token)) = parser.tokens.next() {
+        match token {
+            Token::Word(word) if word == "key" => keys.push(parser.key()?),
+            Token::Word(_) | Token::Quoted(_) => parser.skip_statement()?,
+            token => return Err(syntax(line, format!("Unexpected {token}"))),
+        }
+    }
+    Ok(keys)
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Open,
+    Close,
+    Semicolon,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "{word}"),
+            Token::Quoted(s) => write!(f, "{s:?}"),
+            Token::Open => f.write_str("{"),
+            Token::Close => f.write_str("}"),
+            Token::Semicolon => f.write_str(";"),
+        }
+    }
+}
+
+/// Split into tokens with their line numbers, dropping comments
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, Error> {
+    let mut tokens = vec![];
+    let mut line = 1;
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '#' => {
+                skip_line(&mut chars);
+                line += 1;
+                continue;
+            }
+            '/' if chars.peek() == Some(&'/') => {
+                skip_line(&mut chars);
+                line += 1;
+                continue;
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                let start = line;
+                chars.next();
+                let mut prev = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if prev == '*' => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            prev = c;
+                        }
+                        None => return Err(syntax(start, "Unterminated comment".to_string())),
+                    }
+                }
+                continue;
+            }
+            '{' => Token::Open,
+            '}' => Token::Close,
+            ';' => Token::Semicolon,
+            '"' => {
+                let start = line;
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => s.extend(chars.next()),
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            s.push(c);
+                        }
+                        None => return Err(syntax(start, "Unterminated string".to_string())),
+                    }
+                }
+                Token::Quoted(s)
+            }
+            c => {
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || "{};\"".contains(c) {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                Token::Word(word)
+            }
+        };
+        tokens.push((line, token));
+    }
+    Ok(tokens)
+}
+
+fn skip_line(chars: &mut Peekable<Chars<'_>>) {
+    for c in chars {
+        if c == '\n' {
+            break;
+        }
+    }
+}
+
+struct Parser {
+    tokens: std::vec::IntoIter<(usize, Token)>,
+    /// Line of the last token
+    line: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Result<Token, Error> {
+        let (line, token) = self
+            .tokens
+            .next()
+            .ok_or_else(|| syntax(self.line, "Unexpected end of file".to_string()))?;
+        self.line = line;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &Token) -> Result<(), Error> {
+        match self.next()? {
+            token if token == *expected => Ok(()),
+            token => Err(syntax(
+                self.line,
+                format!("Expected {expected} instead of {token}"),
+            )),
+        }
+    }
+
+    /// A name or a quoted string
+    fn value(&mut self) -> Result<String, Error> {
+        match self.next()? {
+            Token::Word(s) | Token::Quoted(s) => Ok(s),
+            token => Err(syntax(self.line, format!("Unexpected {token}"))),
+        }
+    }
+
+    /// Skip everything up to the end of the current statement,
+    /// including blocks
+    fn skip_statement(&mut self) -> Result<(), Error> {
+        let mut depth = 0_usize;
+        loop {
+            match self.next()? {
+                Token::Semicolon if depth == 0 => return Ok(()),
+                Token::Open => depth += 1,
+                Token::Close if depth == 0 => {
+                    return Err(syntax(self.line, "Unexpected }".to_string()))
+                }
+                Token::Close => depth -= 1,
+                _ => {}
+            }
+        }
+    }
+
+    /// Parse the rest of a `key` statement
+    fn key(&mut self) -> Result<Key, Error> {
+        let name = self.value()?;
+        let line = self.line;
+        self.expect(&Token::Open)?;
+
+        let (mut algorithm, mut secret) = (None, None);
+        loop {
+            match self.next()? {
+                Token::Close => break,
+                Token::Word(word) if word == "algorithm" => {
+                    algorithm = Some(self.value()?);
+                    self.expect(&Token::Semicolon)?;
+                }
+                Token::Word(word) if word == "secret" => {
+                    secret = Some(Secret::from(self.value()?));
+                    self.expect(&Token::Semicolon)?;
+                }
+                Token::Word(_) | Token::Quoted(_) => self.skip_statement()?,
+                token => return Err(syntax(self.line, format!("Unexpected {token}"))),
+            }
+        }
+        self.expect(&Token::Semicolon)?;
+
+        match (algorithm, secret) {
+            (Some(algorithm), Some(secret)) => Ok(Key {
+                name,
+                algorithm,
+                secret,
+            }),
+            (None, _) => Err(syntax(line, format!("Key {name} has no algorithm"))),
+            (_, None) => Err(syntax(line, format!("Key {name} has no secret"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn syntax_error(source: &str) -> (usize, String) {
+        match parse(source) {
+            Err(Error::Syntax { line, message }) => (line, message),
+            result => panic!("Expected a syntax error, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn ddns_confgen() {
+        let source = r#"# To activate this key, place the following in named.conf, and
+# in a separate keyfile on the system or systems from which nsupdate
+# will be run:
+key "ddns-key.example.com" {
+	algorithm hmac-sha256;
+	secret "dG9wc2VjcmV0Cg==";
+};
+
+# Then, in the "zone" statement for the zone containing the
+# name "example.com", place an "update-policy" statement
+# like this one, adjusted as needed for your preferred permissions:
+update-policy {
+	  grant ddns-key.example.com name example.com ANY;
+};
+
+# After the keyfile has been placed, the following command will
+# execute nsupdate using this key:
+# nsupdate -k <keyfile>
+"#;
+        let keys = parse(source).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].name, "ddns-key.example.com");
+        assert_eq!(keys[0].algorithm, "hmac-sha256");
+        assert_eq!(keys[0].secret.expose(), b"dG9wc2VjcmV0Cg==");
+    }
+
+    #[test]
+    fn tsig_keygen() {
+        let source = "key \"tsig-key\" {\n\talgorithm hmac-sha512;\n\tsecret \"c2VjcmV0\";\n};\n";
+        let keys = parse(source).unwrap();
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].name, "tsig-key");
+        assert_eq!(keys[0].algorithm, "hmac-sha512");
+        assert_eq!(keys[0].secret.expose(), b"c2VjcmV0");
+    }
+
+    #[test]
+    fn several_keys() {
+        let source = r#"
+options {
+    directory "/var/named"; // nested blocks are skipped
+    listen-on { 127.0.0.1; };
+};
+/* key "commented" { algorithm hmac-md5; secret "eA=="; }; */
+key first. { algorithm hmac-sha256; secret "Zmlyc3Q="; };
+key "second" {
+    algorithm "hmac-sha384";
+    secret "c2Vjb25k";
+};
+"#;
+        let keys = parse(source).unwrap();
+        let names = keys.iter().map(|key| key.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["first.", "second"]);
+        assert_eq!(keys[1].algorithm, "hmac-sha384");
+        assert_eq!(keys[1].secret.expose(), b"c2Vjb25k");
+    }
+
+    #[test]
+    fn missing_secret() {
+        let source = "\nkey \"no-secret\" {\n\talgorithm hmac-sha256;\n};\n";
+        let (line, message) = syntax_error(source);
+        assert_eq!(line, 2);
+        assert_eq!(message, "Key no-secret has no secret");
+    }
+
+    #[test]
+    fn missing_algorithm() {
+        let (_, message) = syntax_error("key k { secret \"eA==\"; };");
+        assert_eq!(message, "Key k has no algorithm");
+    }
+
+    #[test]
+    fn unterminated_comment() {
+        let (line, message) = syntax_error("key k {\n/* algorithm\nhmac-sha256; };\n");
+        assert_eq!(line, 2);
+        assert_eq!(message, "Unterminated comment");
+    }
+
+    #[test]
+    fn unterminated_string() {
+        let (line, message) = syntax_error("key \"k {\n");
+        assert_eq!(line, 1);
+        assert_eq!(message, "Unterminated string");
+    }
+
+    #[test]
+    fn unexpected_end() {
+        let (line, message) = syntax_error("key k {\n algorithm hmac-sha256;\n");
+        assert_eq!(line, 2);
+        assert_eq!(message, "Unexpected end of file");
+    }
+
+    #[test]
+    fn names() {
+        assert!(same_name("Key.Example.com.", "key.example.com"));
+        assert!(!same_name("key.example.com", "key.example.net"));
+    }
+}
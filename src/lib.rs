@@ -1,54 +1,157 @@
+pub mod check;
 pub mod config;
+pub mod control;
 pub mod dns;
 pub mod ifaces;
+pub mod keyfile;
+pub mod manual;
+pub mod metrics;
+pub mod notify;
+pub mod retry;
+pub mod secret;
+pub mod state;
+pub mod status;
+pub mod template;
 
 use cidr::IpCidr;
 use hickory_client::proto::rr::record_type::RecordType;
 use log::{debug, error, info, trace, warn};
 use std::collections::HashMap;
-use std::net::{IpAddr, Ipv6Addr};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::rc::Rc;
 use std::str::FromStr;
 use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::sync::Mutex;
-use tokio::time::timeout;
+use tokio::time::sleep_until;
 
-pub const RETRY_INTERVAL: u64 = 60;
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Config(#[from] config::Error),
+    #[error("Invalid retry settings: {0}")]
+    Retry(#[from] retry::Error),
+    #[error("No such key: {0}")]
+    NoSuchKey(String),
+    #[error("{0} keys are configured, select one")]
+    KeyRequired(usize),
+    #[error("Cannot set up key {key}: {source}")]
+    Server {
+        key: String,
+        #[source]
+        source: dns::Error,
+    },
+    #[error("Cannot handle signals: {0}")]
+    Signal(#[source] std::io::Error),
+    #[error(transparent)]
+    Netlink(#[from] ifaces::Error),
+    #[error("Netlink listener has stopped")]
+    NetlinkStopped,
+    #[error(transparent)]
+    Control(#[from] control::Error),
+    #[error("Cannot serve metrics on {addr}: {source}")]
+    Metrics {
+        addr: SocketAddr,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Cannot update {name}: {source}")]
+    Update {
+        name: String,
+        #[source]
+        source: dns::Error,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Options {
+    /// Query DNS but only log updates instead of sending them
+    pub dry_run: bool,
+}
+
+/// Where the configuration comes from
+pub enum ConfigSource {
+    /// A file or directory that is reloaded on `SIGHUP`
+    File(String),
+    /// Built in memory, e.g. from command line arguments. It is
+    /// validated like a file.
+    Config(Box<config::Config>),
+}
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde_derive::Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum AddressFamily {
     IPv4,
     IPv6,
 }
 
+/// What bringing a name in sync with DNS came to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// DNS already had the address
+    InSync,
+    Updated,
+    /// Dry run: an update would have been sent
+    WouldUpdate,
+    Failed(String),
+}
+
 pub struct RecordState {
+    task: config::UpdateTask,
+    /// Global defaults when this record was configured
+    defaults: config::Defaults,
+    af: AddressFamily,
     server: Rc<Mutex<dns::Server>>,
+    server_addr: IpAddr,
     name: Option<Rc<String>>,
     neighbors: Rc<HashMap<String, Ipv6Addr>>,
 
+    record_type: RecordType,
     addr: Option<IpAddr>,
     ttl: u32,
     zone: Option<Rc<String>>,
     scope: IpCidr,
+    retry: retry::Policy,
+    verify_interval: Option<Duration>,
     dirty: bool,
-    update_tried: Option<Instant>,
+    /// Send the next update even if DNS already has the address
+    forced: bool,
+    failures: u32,
+    retry_at: Option<Instant>,
+    verify_at: Option<Instant>,
+    /// Outcome per name, including neighbors
+    records: HashMap<String, state::Record>,
 }
 
 impl RecordState {
     /// # Panics
     ///
-    /// Will panic if the `scope` setting could not be parsed as a
-    /// Classless Inter-Domain Routing (CIDR) address.
+    /// Will panic if `update_task` has not been validated by
+    /// `config::load()`, e.g. if the `scope` setting could not be
+    /// parsed as a Classless Inter-Domain Routing (CIDR) address.
+    ///
+    /// Zone, TTL and scope fall back to the `default-*` settings of
+    /// `key` and then to the global `defaults`.
     pub fn new(
         update_task: config::UpdateTask,
+        key: &config::TsigKey,
+        defaults: &config::Defaults,
         server: Rc<Mutex<dns::Server>>,
         af: AddressFamily,
+        retry: retry::Policy,
+        verify_interval: Option<Duration>,
     ) -> Self {
-        let scope = IpCidr::from_str(update_task.scope.as_deref().unwrap_or(match af {
-            crate::AddressFamily::IPv4 => "0.0.0.0/0",
-            AddressFamily::IPv6 => "2000::/3",
-        }))
-        .unwrap();
+        let task = update_task.clone();
+        let scope = update_task
+            .scope
+            .as_deref()
+            .or_else(|| config::family_scope(key.default_scope.as_ref(), af))
+            .or_else(|| config::family_scope(defaults.scope.as_ref(), af))
+            .unwrap_or(match af {
+                crate::AddressFamily::IPv4 => "0.0.0.0/0",
+                AddressFamily::IPv6 => "2000::/3",
+            });
+        let scope = IpCidr::from_str(scope).unwrap();
         match af {
             AddressFamily::IPv4 if update_task.neighbors.is_some() => {
                 panic!("neighbors are not supported on IPv4");
@@ -58,7 +161,11 @@ impl RecordState {
             _ => panic!("scope {} doesn't match address family {:?}", scope, af),
         }
 
-        let zone = if let Some(zone) = update_task.zone {
+        let zone = update_task
+            .zone
+            .or_else(|| key.default_zone.clone())
+            .or_else(|| defaults.zone.clone());
+        let zone = if let Some(zone) = zone {
             Some(Rc::new(zone))
         } else {
             warn!("Your configuration misses the `zone` parameter. This field will be mandatory in a future release.");
@@ -75,19 +182,139 @@ impl RecordState {
         let name = update_task.name.map(|name| Rc::new(name.clone()));
 
         RecordState {
+            task,
+            defaults: defaults.clone(),
+            af,
             server,
+            server_addr: key.server,
             name,
             neighbors: Rc::new(update_task.neighbors.unwrap_or_default()),
 
+            record_type: match af {
+                AddressFamily::IPv4 => RecordType::A,
+                AddressFamily::IPv6 => RecordType::AAAA,
+            },
             addr: None,
-            ttl: update_task.ttl.unwrap_or(0),
+            ttl: update_task
+                .ttl
+                .or(key.default_ttl)
+                .or(defaults.ttl)
+                .unwrap_or(0),
             zone,
             scope,
+            retry,
+            verify_interval,
             dirty: false,
-            update_tried: None,
+            forced: false,
+            failures: 0,
+            retry_at: None,
+            verify_at: None,
+            records: HashMap::new(),
+        }
+    }
+
+    /// Whether this record has been created from the same settings
+    fn is_configured_by(
+        &self,
+        af: AddressFamily,
+        task: &config::UpdateTask,
+        defaults: &config::Defaults,
+        server: &Rc<Mutex<dns::Server>>,
+    ) -> bool {
+        self.af == af
+            && self.task == *task
+            && self.defaults == *defaults
+            && Rc::ptr_eq(&self.server, server)
+    }
+
+    fn reconfigure(&mut self, retry: retry::Policy, verify_interval: Option<Duration>) {
+        self.retry = retry;
+        if self.verify_interval != verify_interval {
+            self.verify_interval = verify_interval;
+            if !self.dirty && self.addr.is_some() {
+                self.verify_at =
+                    verify_interval.map(|verify_interval| Instant::now() + verify_interval);
+            }
         }
     }
 
+    /// All names with the addresses they get for `addr`
+    fn targets(&self, addr: IpAddr) -> Vec<(String, IpAddr)> {
+        let mut targets = vec![];
+        if let Some(name) = &self.name {
+            targets.push((name.to_string(), addr));
+        }
+        if let IpAddr::V6(addr) = addr {
+            for (neighbor_name, neighbor_addr) in &*self.neighbors {
+                targets.push((
+                    neighbor_name.clone(),
+                    neighbor_address(addr, *neighbor_addr).into(),
+                ));
+            }
+        }
+        targets
+    }
+
+    /// Pick up where a previous run left off
+    pub fn restore(&mut self, state: &state::State) {
+        let names = self
+            .name
+            .iter()
+            .map(ToString::to_string)
+            .chain(self.neighbors.keys().cloned())
+            .collect::<Vec<_>>();
+        for name in names {
+            if let Some(record) = state.records.get(&state::key(&name, self.record_type)) {
+                self.records.insert(name, record.clone());
+            }
+        }
+
+        if let Some(record) = self
+            .name
+            .as_ref()
+            .and_then(|name| self.records.get(&**name))
+        {
+            let (failures, retry_at) = (record.failures, record.retry_at);
+            self.failures = failures;
+            self.retry_at = retry_at.map(state::from_unix_time);
+        }
+    }
+
+    /// State to persist, keyed by `state::key()`
+    #[must_use]
+    pub fn state_records(&self) -> Vec<(String, state::Record)> {
+        self.records
+            .iter()
+            .map(|(name, record)| {
+                let mut record = record.clone();
+                if self.name.as_deref().map(String::as_str) == Some(name) {
+                    record.failures = self.failures;
+                    record.retry_at = self.retry_at.map(state::to_unix_time);
+                }
+                (state::key(name, self.record_type), record)
+            })
+            .collect()
+    }
+
+    /// Whether all names already point to `addr` according to what
+    /// has been restored, on the same server, zone and key
+    fn is_published(&self, addr: IpAddr) -> bool {
+        self.targets(addr).iter().all(|(name, addr)| {
+            self.records.get(name).is_some_and(|record| {
+                record.addr == Some(*addr)
+                    && record.ttl == self.ttl
+                    && record.server == Some(self.server_addr)
+                    && record.zone.as_deref() == self.zone.as_deref().map(String::as_str)
+                    && record.key.as_ref() == Some(&self.task.key)
+            })
+        })
+    }
+
+    #[must_use]
+    pub fn interface(&self) -> &str {
+        &self.task.interface
+    }
+
     pub fn set_address(&mut self, addr: IpAddr) -> bool {
         // check scope
         if !self.scope.contains(&addr) {
@@ -99,89 +326,242 @@ impl RecordState {
             return false;
         }
 
+        if self.addr.is_none() && self.is_published(addr) {
+            debug!("{addr} has already been published before restart");
+            self.addr = Some(addr);
+            self.retry_at = None;
+            self.failures = 0;
+            self.verify_at = self
+                .verify_interval
+                .map(|verify_interval| Instant::now() + verify_interval);
+            return false;
+        }
+
+        if self.addr.is_some() {
+            // Retry timers that were restored only apply to the first address
+            self.failures = 0;
+            self.retry_at = None;
+        }
         self.addr = Some(addr);
         self.dirty = true;
-        self.update_tried = None;
         true
     }
 
     #[must_use]
     pub fn can_update(&self) -> bool {
-        match self.update_tried {
-            // nothing to do
-            _ if !self.dirty => false,
+        match self.retry_at {
+            // re-verify what has been published
+            _ if !self.dirty => self
+                .verify_at
+                .is_some_and(|verify_at| Instant::now() >= verify_at),
 
             // new
             None => true,
 
-            // retry if RETRY_INTERVAL elapsed
-            Some(update_tried) => {
-                Instant::now() >= update_tried + Duration::from_secs(RETRY_INTERVAL)
-            }
+            // retry if the backoff delay elapsed
+            Some(retry_at) => Instant::now() >= retry_at,
         }
     }
 
     #[must_use]
     pub fn next_timeout(&self) -> Option<Instant> {
         if self.dirty {
-            self.update_tried
-                .map(|update_tried| update_tried + Duration::from_secs(RETRY_INTERVAL))
-                .or_else(|| Some(Instant::now()))
+            self.retry_at.or_else(|| Some(Instant::now()))
         } else {
-            None
+            self.verify_at
         }
     }
+    /// Whether `name` is the name of this record or of one of its
+    /// neighbors
+    fn has_name(&self, name: &str) -> bool {
+        self.name.as_deref().map(String::as_str) == Some(name) || self.neighbors.contains_key(name)
+    }
+
+    /// Send an update at the next opportunity, even if DNS already
+    /// has the address and while a retry is pending
+    pub fn force_update(&mut self) {
+        if self.addr.is_some() {
+            self.dirty = true;
+            self.forced = true;
+            self.retry_at = None;
+        }
+    }
+
+    /// Query DNS at the next opportunity, and update if it differs
+    pub fn verify(&mut self) {
+        if self.addr.is_some() && !self.dirty {
+            self.verify_at = Some(Instant::now());
+        }
+    }
+
+    /// State of the name and all neighbors, for the control socket
+    #[must_use]
+    pub fn status(&self) -> Vec<control::RecordStatus> {
+        let names = self
+            .name
+            .iter()
+            .map(ToString::to_string)
+            .chain(self.neighbors.keys().cloned());
+        let addrs = self.addr.map(|addr| self.targets(addr)).unwrap_or_default();
+        names
+            .map(|name| {
+                let record = self.records.get(&name);
+                control::RecordStatus {
+                    address: addrs
+                        .iter()
+                        .find(|(target, _)| *target == name)
+                        .map(|(_, addr)| *addr),
+                    published: record.and_then(|record| record.addr),
+                    last_update: record.and_then(|record| record.last_success),
+                    last_error: record.and_then(|record| record.error.clone()),
+                    next_retry: self
+                        .retry_at
+                        .filter(|_| self.dirty)
+                        .map(state::to_unix_time),
+                    name,
+                    record_type: self.record_type.to_string(),
+                    interface: self.task.interface.clone(),
+                }
+            })
+            .collect()
+    }
+
+    /// Returns the outcome for every name that has been tried
+    ///
     /// # Panics
     ///
     /// Will panic if the config (`[[aaaa]]` or `[[a]]`) misses an `address`.
-    pub async fn update(&mut self) {
+    pub async fn update(&mut self) -> Vec<(String, Outcome)> {
         self.dirty = false;
-        self.update_tried = Some(Instant::now());
+        let forced = std::mem::take(&mut self.forced);
 
         let addr = self.addr.unwrap();
+        let mut outcomes = vec![];
         if let Some(name) = &self.name.clone() {
-            if let Err(e) = self.update_addr(name, &addr).await {
-                error!("Error updating {} to {}: {}", name, self.addr.unwrap(), e);
+            let result = self.update_addr(name, &addr, forced).await;
+            outcomes.push((name.to_string(), self.record_result(name, addr, &result)));
+            if let Err(e) = result {
                 // try again later
+                let class = e.class();
+                self.failures += 1;
+                let delay = self.retry.delay(class, self.failures);
+                self.retry_at = Some(Instant::now() + delay);
                 self.dirty = true;
-                return;
+
+                if class.is_transient() {
+                    error!("Error updating {name} to {addr}: {e}, retrying in {delay:.0?}");
+                } else {
+                    error!(
+                        "Permanent failure ({class}) updating {name} to {addr}: {e}, retrying in {delay:.0?}"
+                    );
+                }
+                return outcomes;
             }
         }
+        self.failures = 0;
+        self.retry_at = None;
+        self.verify_at = self
+            .verify_interval
+            .map(|verify_interval| Instant::now() + verify_interval);
 
         if let Some(IpAddr::V6(addr)) = self.addr {
             for (neighbor_name, neighbor_addr) in &*self.neighbors.clone() {
-                let net_segs = addr.segments();
-                let host_segs = neighbor_addr.segments();
-                let addr = Ipv6Addr::new(
-                    net_segs[0],
-                    net_segs[1],
-                    net_segs[2],
-                    net_segs[3],
-                    host_segs[4],
-                    host_segs[5],
-                    host_segs[6],
-                    host_segs[7],
-                )
-                .into();
-
-                if let Err(e) = self.update_addr(neighbor_name, &addr).await {
+                let addr = neighbor_address(addr, *neighbor_addr).into();
+
+                let result = self.update_addr(neighbor_name, &addr, forced).await;
+                outcomes.push((
+                    neighbor_name.clone(),
+                    self.record_result(neighbor_name, addr, &result),
+                ));
+                if let Err(e) = result {
                     error!("Error updating neighbor {neighbor_addr} to {addr}: {e}");
                 }
             }
         }
+        outcomes
     }
 
-    async fn update_addr(&mut self, name: &str, addr: &IpAddr) -> Result<(), String> {
-        let record_type = match addr {
-            IpAddr::V4(_) => RecordType::A,
-            IpAddr::V6(_) => RecordType::AAAA,
-        };
+    /// Nothing is recorded for a dry run, as nothing has been published
+    fn record_result(
+        &mut self,
+        name: &str,
+        addr: IpAddr,
+        result: &Result<Outcome, dns::Error>,
+    ) -> Outcome {
+        if let Ok(Outcome::WouldUpdate) = result {
+            return Outcome::WouldUpdate;
+        }
+
+        let now = state::now();
+        let record = self.records.entry(name.to_string()).or_default();
+        record.time = now;
+        match result {
+            Ok(outcome) => {
+                record.addr = Some(addr);
+                record.ttl = self.ttl;
+                record.server = Some(self.server_addr);
+                record.zone = self.zone.as_deref().cloned();
+                record.key = Some(self.task.key.clone());
+                record.last_success = Some(now);
+                record.error = None;
+                outcome.clone()
+            }
+            Err(e) => {
+                record.error = Some(e.to_string());
+                Outcome::Failed(e.to_string())
+            }
+        }
+    }
+
+    /// Delete all records that have been published
+    pub async fn withdraw(&mut self) {
+        self.addr = None;
+        self.dirty = false;
+        self.retry_at = None;
+        self.verify_at = None;
+
+        let zone = self.zone.clone();
+        let zone = zone.as_ref().map(|zone| zone.as_str());
+        let mut server = self.server.lock().await;
+        for (name, record) in &mut self.records {
+            if record.addr.is_none() {
+                continue;
+            }
+
+            record.time = state::now();
+            match server.delete(name, self.record_type, zone).await {
+                Ok(()) => {
+                    info!("Withdrew {} {name}", self.record_type);
+                    record.addr = None;
+                    record.error = None;
+                }
+                Err(e) => {
+                    error!("Error withdrawing {} {name}: {e}", self.record_type);
+                    record.error = Some(e.to_string());
+                }
+            }
+        }
+    }
+
+    async fn update_addr(
+        &mut self,
+        name: &str,
+        addr: &IpAddr,
+        forced: bool,
+    ) -> Result<Outcome, dns::Error> {
+        let record_type = self.record_type;
 
         let mut server = self.server.lock().await;
         match server.query(name, record_type).await {
-            Ok(addrs) if addrs.len() == 1 && addrs[0] == *addr => {
+            Ok(_) if forced => {
+                info!("Forced update of {name}");
+            }
+            Ok(addrs) if addrs.len() == 1 && addrs[0] == (*addr, self.ttl) => {
                 info!("No address change for {name} ({addr} == {addrs:?})");
-                return Ok(());
+                return Ok(Outcome::InSync);
+            }
+            Ok(addrs) if addrs.len() == 1 && addrs[0].0 == *addr => {
+                info!("Outdated TTL for {name}: {} != {}", addrs[0].1, self.ttl);
             }
             Ok(addrs) => {
                 info!("Outdated address for {name}: {addrs:?}");
@@ -192,105 +572,741 @@ impl RecordState {
         }
 
         let zone = self.zone.as_ref().map(|zone| zone.as_str());
+        if server.is_dry_run() {
+            server.update(name, *addr, zone, self.ttl).await?;
+            return Ok(Outcome::WouldUpdate);
+        }
 
-        server.update(name, *addr, zone, self.ttl).await
+        let record_type = record_type.to_string();
+        metrics::update_attempted(name, &record_type);
+        let result = server.update(name, *addr, zone, self.ttl).await;
+        metrics::update_result(
+            name,
+            &record_type,
+            result.as_ref().map(drop).map_err(dns::Error::class),
+        );
+        result.map(|()| Outcome::Updated)
     }
 }
+/// Combine the network part of `net` with the host part of `host`
+fn neighbor_address(net: Ipv6Addr, host: Ipv6Addr) -> Ipv6Addr {
+    let net_segs = net.segments();
+    let host_segs = host.segments();
+    Ipv6Addr::new(
+        net_segs[0],
+        net_segs[1],
+        net_segs[2],
+        net_segs[3],
+        host_segs[4],
+        host_segs[5],
+        host_segs[6],
+        host_segs[7],
+    )
+}
+
+/// Runs until SIGTERM or SIGINT is received.
+///
 /// # Errors
 ///
-/// Will return `Err` if `config_file` does not exist or the user does not have
-/// permission to read it.
+/// Will return `Err` if
+///
+/// - The configuration cannot be loaded or is invalid.
+/// - A key cannot be set up.
+/// - Signal handlers cannot be installed.
+/// - The netlink listener stops.
+pub async fn run(source: ConfigSource, options: Options) -> Result<(), Error> {
+    let mut daemon = Daemon::new(source, options).await?;
+    daemon.run().await
+}
+
+/// Ping systemd's watchdog unless the netlink listener is stuck, so
+/// that systemd restarts us then
+fn ping_watchdog() {
+    if ifaces::is_alive() {
+        notify::watchdog();
+    } else {
+        warn!("Netlink listener is not responding");
+    }
+}
+
+/// Take a single address dump, reconcile all records and neighbors
+/// with DNS, and print a summary.
+///
+/// Returns whether all updates have succeeded.
 ///
-/// # Panics
+/// # Errors
 ///
-/// Will panic if the config (`[[aaaa]]` or `[[a]]`) misses a key.
-pub async fn run(config_file: &str) -> Result<(), String> {
-    const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
-    const NEVER_TIMEOUT: Duration = Duration::from_secs(365 * 86400);
-
-    let config = config::load(config_file)?;
-
-    let keys = config
-        .keys
-        .into_iter()
-        .map(|(name, key)| (name, Rc::new(key)))
-        .collect::<HashMap<_, _>>();
-    let mut servers = HashMap::new();
-    for (name, key) in &keys {
-        servers.insert(
-            name,
-            Rc::new(Mutex::new(dns::Server::new(key.server, key).await)),
-        );
+/// Will return `Err` if the configuration cannot be loaded or is
+/// invalid, a key cannot be set up, or netlink cannot be queried.
+pub async fn run_once(source: ConfigSource, options: Options) -> Result<bool, Error> {
+    let mut daemon = Daemon::new(source, options).await?;
+    for (iface, addr) in ifaces::dump().await? {
+        daemon.set_address(&iface, addr);
     }
-    let mut iface_states = HashMap::<String, Vec<RecordState>>::new();
-    for a in config.a.unwrap_or_default() {
-        let server = servers.get(&a.key).unwrap();
-        iface_states
-            .entry(a.interface.clone())
-            .or_default()
-            .push(RecordState::new(a, server.clone(), AddressFamily::IPv4));
-    }
-    for aaaa in config.aaaa.unwrap_or_default() {
-        let server = servers.get(&aaaa.key).unwrap();
-        iface_states
-            .entry(aaaa.interface.clone())
-            .or_default()
-            .push(RecordState::new(aaaa, server.clone(), AddressFamily::IPv6));
-    }
-
-    let mut interval = NEVER_TIMEOUT;
-
-    let mut addr_updates = ifaces::start();
-
-    loop {
-        trace!("recv for {interval:?}");
-        match timeout(interval, addr_updates.recv()).await {
-            Ok(Some((iface, addr))) => {
-                trace!("interface {iface}: address {addr}");
-                if let Some(states) = iface_states.get_mut(&iface) {
-                    for record_state in &mut *states {
-                        if record_state.set_address(addr) {
-                            debug!("interface {iface}: new address {addr}");
-                            interval = IDLE_TIMEOUT;
+
+    let (mut succeeded, mut failed, mut would_update) = (0, 0, 0);
+    for record_state in daemon.iface_states.values_mut().flatten() {
+        let Some(addr) = record_state.addr else {
+            println!(
+                "{} {}: no address on {}",
+                record_state
+                    .name
+                    .as_deref()
+                    .map_or("(neighbors)", String::as_str),
+                record_state.record_type,
+                record_state.interface()
+            );
+            continue;
+        };
+
+        let outcomes = record_state.update().await;
+        for (name, addr) in record_state.targets(addr) {
+            let outcome = outcomes
+                .iter()
+                .find(|(target, _)| *target == name)
+                .map(|(_, outcome)| outcome);
+            let outcome = match outcome {
+                None => "skipped".to_string(),
+                Some(Outcome::Failed(e)) => {
+                    failed += 1;
+                    e.clone()
+                }
+                Some(Outcome::WouldUpdate) => {
+                    would_update += 1;
+                    "would update".to_string()
+                }
+                Some(Outcome::InSync | Outcome::Updated) => {
+                    succeeded += 1;
+                    "ok".to_string()
+                }
+            };
+            println!("{name} {} {addr}: {outcome}", record_state.record_type);
+        }
+    }
+    daemon.save_state();
+
+    if daemon.options.dry_run {
+        println!("{succeeded} records in sync, {would_update} would be updated, {failed} failed");
+    } else {
+        println!("{succeeded} records in sync, {failed} failed");
+    }
+    Ok(failed == 0)
+}
+
+struct Daemon {
+    /// `None` if the configuration has not been loaded from a file
+    config_file: Option<String>,
+    options: Options,
+    state_file: Option<String>,
+    /// Only read at startup
+    control_socket: Option<String>,
+    /// Only read at startup
+    metrics_listen: Option<SocketAddr>,
+    /// Updates are paused through the control socket
+    paused: bool,
+    servers: HashMap<String, (Rc<config::TsigKey>, Rc<Mutex<dns::Server>>)>,
+    iface_states: HashMap<String, Vec<RecordState>>,
+    /// Addresses of all interfaces, to set up records after reload
+    iface_addrs: HashMap<String, Vec<IpAddr>>,
+}
+
+impl Daemon {
+    async fn new(source: ConfigSource, options: Options) -> Result<Self, Error> {
+        let (config, config_file) = match source {
+            ConfigSource::File(config_file) => (config::load(&config_file)?, Some(config_file)),
+            ConfigSource::Config(config) => (config::validate(*config)?, None),
+        };
+        let mut daemon = Daemon {
+            config_file,
+            options,
+            state_file: None,
+            control_socket: None,
+            metrics_listen: None,
+            paused: false,
+            servers: HashMap::new(),
+            iface_states: HashMap::new(),
+            iface_addrs: HashMap::new(),
+        };
+        daemon.configure(config, true).await?;
+        Ok(daemon)
+    }
+
+    /// Reload the config file. Keeps the running configuration if the
+    /// new one is invalid.
+    async fn reload(&mut self) {
+        let Some(config_file) = &self.config_file else {
+            info!("No configuration file to reload");
+            return;
+        };
+        info!("Reloading {config_file}");
+        let result = match config::load(config_file) {
+            Ok(config) => self.configure(config, false).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = result {
+            error!("Keeping the previous configuration: {e}");
+        }
+    }
+
+    /// Apply a configuration. Records with unchanged settings keep
+    /// their state, new ones get the current interface addresses.
+    /// Persisted state only applies at `startup`, so that records which
+    /// are new or have been changed by a reload are checked again.
+    async fn configure(&mut self, config: config::Config, startup: bool) -> Result<(), Error> {
+        let retry = retry::Policy::new(config.retry.as_ref())?;
+        let verify_interval = config.verify_interval.map(Duration::from_secs);
+        let state_file = config.state_file.clone().or_else(state::default_path);
+        let control_socket = config.control_socket.clone().or_else(control::default_path);
+        let tasks = config.tasks();
+        let defaults = config.defaults.unwrap_or_default();
+        let keys = config.keys;
+        if let Some((_, task)) = tasks.iter().find(|(_, task)| !keys.contains_key(&task.key)) {
+            return Err(Error::NoSuchKey(task.key.clone()));
+        }
+
+        let mut servers = HashMap::new();
+        for (name, key) in keys {
+            let server = match self.servers.get(&name) {
+                Some((old_key, server)) if **old_key == key => (old_key.clone(), server.clone()),
+                _ => {
+                    debug!("Setting up key {name}");
+                    let mut server =
+                        dns::Server::new(key.server, &key).await.map_err(|source| {
+                            Error::Server {
+                                key: name.clone(),
+                                source,
+                            }
+                        })?;
+                    server.set_dry_run(self.options.dry_run);
+                    (Rc::new(key), Rc::new(Mutex::new(server)))
+                }
+            };
+            servers.insert(name, server);
+        }
+
+        let persisted = match &state_file {
+            Some(state_file) if startup && std::path::Path::new(state_file).exists() => {
+                state::load(state_file).unwrap_or_else(|e| {
+                    warn!("Cannot load state from {state_file}: {e}");
+                    state::State::default()
+                })
+            }
+            _ => state::State::default(),
+        };
+
+        let mut old_states = std::mem::take(&mut self.iface_states)
+            .into_values()
+            .flatten()
+            .collect::<Vec<_>>();
+        let mut iface_states = HashMap::<String, Vec<RecordState>>::new();
+        for (af, task) in tasks {
+            let (key, server) = &servers[&task.key];
+            let record_state = if let Some(index) = old_states.iter().position(|record_state| {
+                record_state.is_configured_by(af, &task, &defaults, server)
+            }) {
+                let mut record_state = old_states.swap_remove(index);
+                record_state.reconfigure(retry, verify_interval);
+                record_state
+            } else {
+                info!(
+                    "New record {} on {}",
+                    task.name.as_deref().unwrap_or("(neighbors)"),
+                    task.interface
+                );
+                let mut record_state = RecordState::new(
+                    task,
+                    key,
+                    &defaults,
+                    server.clone(),
+                    af,
+                    retry,
+                    verify_interval,
+                );
+                record_state.restore(&persisted);
+                for addr in self
+                    .iface_addrs
+                    .get(record_state.interface())
+                    .into_iter()
+                    .flatten()
+                {
+                    record_state.set_address(*addr);
+                }
+                record_state
+            };
+            iface_states
+                .entry(record_state.interface().to_string())
+                .or_default()
+                .push(record_state);
+        }
+        for record_state in old_states {
+            info!(
+                "Removed record {} on {}",
+                record_state.task.name.as_deref().unwrap_or("(neighbors)"),
+                record_state.task.interface
+            );
+        }
+
+        self.servers = servers;
+        self.iface_states = iface_states;
+        self.state_file = state_file;
+        self.control_socket = control_socket;
+        self.metrics_listen = config.metrics_listen;
+        Ok(())
+    }
+
+    async fn run(&mut self) -> Result<(), Error> {
+        const IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+        let mut hangup = signal(SignalKind::hangup()).map_err(Error::Signal)?;
+        let mut terminate = signal(SignalKind::terminate()).map_err(Error::Signal)?;
+        let mut interrupt = signal(SignalKind::interrupt()).map_err(Error::Signal)?;
+        // Updates wait until addresses have not changed for IDLE_TIMEOUT
+        let mut settled = Instant::now();
+        let mut initial_dump_done = false;
+
+        let mut addr_updates = ifaces::start();
+        let metrics_listen = self.metrics_listen;
+        if let Some(addr) = metrics_listen {
+            metrics::listen(addr)
+                .await
+                .map_err(|source| Error::Metrics { addr, source })?;
+        }
+        // Removed again on every return from here
+        let control_socket = self.control_socket.clone();
+        let mut control_requests = control_socket.as_deref().map(control::listen).transpose()?;
+        let mut result = Ok(());
+        let mut watchdog = notify::watchdog_interval().map(tokio::time::interval);
+        let mut summary = String::new();
+
+        // Signals are only handled between updates so that these are
+        // never cut off.
+        loop {
+            // An absolute deadline, so that events which change nothing
+            // cannot postpone updates, retries and verification
+            let deadline = if self.paused {
+                None
+            } else {
+                self.next_timeout()
+                    .map(|next_timeout| next_timeout.max(settled))
+            };
+            trace!("next update at {deadline:?}");
+            tokio::select! {
+                event = addr_updates.recv() => match event {
+                    Some(ifaces::Event::InitialDumpDone(interfaces)) if !initial_dump_done => {
+                        initial_dump_done = true;
+                        self.withdraw_missing(&interfaces).await;
+                        summary = self.summary();
+                        notify::ready(&summary);
+                    }
+                    Some(ifaces::Event::InitialDumpDone(_)) => {}
+                    Some(ifaces::Event::Address(iface, addr)) => {
+                        if self.set_address(&iface, addr) {
+                            settled = Instant::now() + IDLE_TIMEOUT;
                         }
                     }
+                    Some(ifaces::Event::AddressRemoved(iface, addr)) => {
+                        trace!("interface {iface}: address {addr} removed");
+                        if let Some(addrs) = self.iface_addrs.get_mut(&iface) {
+                            addrs.retain(|a| *a != addr);
+                        }
+                    }
+                    None => {
+                        result = Err(Error::NetlinkStopped);
+                        break;
+                    }
+                },
+                () = async {
+                    match deadline {
+                        Some(deadline) => sleep_until(deadline.into()).await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    debug!("Deadline reached");
+                    self.send_update().await;
+                    self.save_state();
+                }
+                Some((request, reply)) = async {
+                    match &mut control_requests {
+                        Some(control_requests) => control_requests.recv().await,
+                        None => std::future::pending().await,
+                    }
+                } => {
+                    let _ = reply.send(self.control(request));
+                }
+                Some(_) = async {
+                    match &mut watchdog {
+                        Some(watchdog) => Some(watchdog.tick().await),
+                        None => std::future::pending().await,
+                    }
+                } => ping_watchdog(),
+                _ = hangup.recv() => {
+                    self.reload().await;
+                    self.save_state();
                 }
+                _ = terminate.recv() => break,
+                _ = interrupt.recv() => break,
             }
-            Ok(None) => {
-                error!("netlink disconnect");
-                return Err("finished".to_string());
+            if metrics_listen.is_some() {
+                metrics::set_records(&self.statuses());
             }
-            Err(_) => {
-                /* IDLE_TIMEOUT reached */
-                interval = NEVER_TIMEOUT;
-                debug!("IDLE_TIMEOUT");
-
-                'send_update: for states in iface_states.values_mut() {
-                    for state in &mut *states {
-                        if state.can_update() {
-                            state.update().await;
-                            break 'send_update;
-                        }
-                    }
+            if initial_dump_done {
+                self.notify_status(&mut summary);
+            }
+        }
+
+        if result.is_ok() {
+            info!("Shutting down");
+            notify::stopping();
+            self.shut_down().await;
+        }
+        if let Some(control_socket) = &control_socket {
+            control::remove(control_socket);
+        }
+        result
+    }
+
+    /// Tell systemd about a changed `summary`
+    fn notify_status(&self, summary: &mut String) {
+        let new_summary = self.summary();
+        if new_summary != *summary {
+            *summary = new_summary;
+            notify::status(summary);
+        }
+    }
+
+    /// Withdraw records of interfaces that went away while we were not
+    /// running. Interfaces that exist but have no address yet, e.g.
+    /// while PPP or DHCP are coming up, keep their records.
+    async fn withdraw_missing(&mut self, interfaces: &[String]) {
+        for (interface, states) in &mut self.iface_states {
+            if interfaces.contains(interface) {
+                continue;
+            }
+            for record_state in states {
+                record_state.withdraw().await;
+            }
+        }
+        self.save_state();
+    }
+
+    /// Withdraw records as configured
+    async fn shut_down(&mut self) {
+        for record_state in self.iface_states.values_mut().flatten() {
+            if record_state.task.withdraw_on_exit == Some(true) {
+                record_state.withdraw().await;
+            }
+        }
+        self.save_state();
+    }
+
+    /// State of all records and neighbors
+    fn statuses(&self) -> Vec<control::RecordStatus> {
+        self.iface_states
+            .values()
+            .flatten()
+            .flat_map(RecordState::status)
+            .collect()
+    }
+
+    /// Like "3 records synced, 1 retrying", for systemd's `STATUS=`
+    fn summary(&self) -> String {
+        let (mut synced, mut retrying, mut pending, mut waiting) = (0, 0, 0, 0);
+        for status in self.statuses() {
+            match status.address {
+                None => waiting += 1,
+                address if address == status.published => synced += 1,
+                Some(_) if status.next_retry.is_some() => retrying += 1,
+                Some(_) => pending += 1,
+            }
+        }
+        let mut parts = vec![format!("{synced} records synced")];
+        for (count, state) in [
+            (retrying, "retrying"),
+            (pending, "pending"),
+            (waiting, "without address"),
+        ] {
+            if count > 0 {
+                parts.push(format!("{count} {state}"));
+            }
+        }
+        if self.paused {
+            parts.push("updates paused".to_string());
+        }
+        parts.join(", ")
+    }
+
+    /// Handle a request from the control socket
+    fn control(&mut self, request: control::Request) -> control::Response {
+        let (name, action): (_, fn(&mut RecordState)) = match request {
+            control::Request::List => (None, |_| {}),
+            control::Request::Update { name } => (name, RecordState::force_update),
+            control::Request::Verify { name } => (name, RecordState::verify),
+            control::Request::Pause => {
+                info!("Pausing updates");
+                self.paused = true;
+                (None, |_| {})
+            }
+            control::Request::Resume => {
+                info!("Resuming updates");
+                self.paused = false;
+                (None, |_| {})
+            }
+        };
+
+        let mut records = vec![];
+        for record_state in self.iface_states.values_mut().flatten() {
+            if name
+                .as_deref()
+                .is_none_or(|name| record_state.has_name(name))
+            {
+                action(record_state);
+                records.extend(
+                    record_state
+                        .status()
+                        .into_iter()
+                        .filter(|status| name.as_ref().is_none_or(|name| status.name == *name)),
+                );
+            }
+        }
+        records.sort_by(|a, b| (&a.name, &a.record_type).cmp(&(&b.name, &b.record_type)));
+        let error = match name {
+            Some(name) if records.is_empty() => Some(format!("No record named {name}")),
+            _ => None,
+        };
+        control::Response {
+            error,
+            paused: self.paused,
+            records,
+        }
+    }
+
+    /// Returns whether any record needs to be updated
+    fn set_address(&mut self, iface: &str, addr: IpAddr) -> bool {
+        trace!("interface {iface}: address {addr}");
+        let addrs = self.iface_addrs.entry(iface.to_string()).or_default();
+        addrs.retain(|a| *a != addr);
+        addrs.push(addr);
+
+        let mut changed = false;
+        if let Some(states) = self.iface_states.get_mut(iface) {
+            for record_state in &mut *states {
+                if record_state.set_address(addr) {
+                    debug!("interface {iface}: new address {addr}");
+                    changed = true;
                 }
+            }
+        }
+        changed
+    }
 
-                /* if NEVER_TIMEOUT was set, find a smaller timeout to retry an update */
-                for states in iface_states.values() {
-                    for state in states {
-                        if let Some(state_timeout) = state.next_timeout() {
-                            let now = Instant::now();
-                            if state_timeout <= now {
-                                interval = Duration::from_secs(0);
-                            } else {
-                                let state_interval = now - state_timeout;
-                                if state_interval < interval {
-                                    interval = state_interval;
-                                }
-                            }
-                        }
-                    }
+    /// Update one record that is due
+    async fn send_update(&mut self) {
+        'send_update: for states in self.iface_states.values_mut() {
+            for state in &mut *states {
+                if state.can_update() {
+                    state.update().await;
+                    break 'send_update;
                 }
             }
         }
     }
+
+    /// When the next record is due
+    fn next_timeout(&self) -> Option<Instant> {
+        self.iface_states
+            .values()
+            .flatten()
+            .filter_map(RecordState::next_timeout)
+            .min()
+    }
+
+    fn save_state(&self) {
+        let Some(state_file) = &self.state_file else {
+            return;
+        };
+        if self.options.dry_run {
+            // Nothing has actually been published
+            return;
+        }
+
+        let state = state::State {
+            records: self
+                .iface_states
+                .values()
+                .flatten()
+                .flat_map(RecordState::state_records)
+                .collect(),
+        };
+        if let Err(e) = state::save(state_file, &state) {
+            error!("Cannot save state to {state_file}: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+[keys.k]
+server = "127.0.0.1"
+name = "k"
+alg = "hmac-sha256"
+secret-base64 = "dG9wc2VjcmV0"
+
+[[a]]
+key = "k"
+name = "up.example.net"
+zone = "example.net"
+interface = "eth0"
+ttl = 60
+
+[[a]]
+key = "k"
+name = "gone.example.net"
+zone = "example.net"
+interface = "gone0"
+ttl = 60
+"#;
+
+    fn published(name: &str, addr: &str, zone: &str) -> (String, state::Record) {
+        (
+            state::key(name, RecordType::A),
+            state::Record {
+                addr: Some(addr.parse().unwrap()),
+                ttl: 60,
+                server: Some("127.0.0.1".parse().unwrap()),
+                zone: Some(zone.to_string()),
+                key: Some("k".to_string()),
+                ..state::Record::default()
+            },
+        )
+    }
+
+    /// Save `records` to a state file for `test` and add it to
+    /// `config`. Returns the configuration and the state file.
+    fn with_state(
+        config: &str,
+        records: Vec<(String, state::Record)>,
+        test: &str,
+    ) -> (config::Config, String) {
+        let state_file =
+            std::env::temp_dir().join(format!("ifdyndnsd-{test}-{}.toml", std::process::id()));
+        let state_file = state_file.to_str().unwrap().to_string();
+        let state = state::State {
+            records: records.into_iter().collect(),
+        };
+        state::save(&state_file, &state).unwrap();
+
+        let config = toml::from_str(&format!("state-file = {state_file:?}\n{config}")).unwrap();
+        (config::validate(config).unwrap(), state_file)
+    }
+
+    /// Start a dry run daemon from `config` that restores `records`
+    async fn daemon(config: &str, records: Vec<(String, state::Record)>, test: &str) -> Daemon {
+        let (config, state_file) = with_state(config, records, test);
+        let daemon = Daemon::new(
+            ConfigSource::Config(Box::new(config)),
+            Options { dry_run: true },
+        )
+        .await;
+        std::fs::remove_file(state_file).unwrap();
+        daemon.unwrap()
+    }
+
+    fn record<'a>(daemon: &'a Daemon, name: &str) -> &'a RecordState {
+        daemon
+            .iface_states
+            .values()
+            .flatten()
+            .find(|record_state| record_state.name.as_deref().map(String::as_str) == Some(name))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn skip_published() {
+        let mut daemon = daemon(
+            CONFIG,
+            vec![published("up.example.net", "192.0.2.1", "example.net")],
+            "skip-published",
+        )
+        .await;
+        assert!(!daemon.set_address("eth0", "192.0.2.1".parse().unwrap()));
+        assert!(!record(&daemon, "up.example.net").can_update());
+
+        // Another address
+        assert!(daemon.set_address("eth0", "192.0.2.2".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn publish_moved() {
+        let mut moved = vec![
+            published("up.example.net", "192.0.2.1", "example.org"),
+            published("up.example.net", "192.0.2.1", "example.net"),
+            published("up.example.net", "192.0.2.1", "example.net"),
+            published("up.example.net", "192.0.2.1", "example.net"),
+        ];
+        moved[1].1.server = Some("127.0.0.2".parse().unwrap());
+        moved[2].1.key = Some("other".to_string());
+        moved[3].1.server = None;
+        for (i, record) in moved.into_iter().enumerate() {
+            let mut daemon = daemon(CONFIG, vec![record], &format!("publish-moved-{i}")).await;
+            assert!(
+                daemon.set_address("eth0", "192.0.2.1".parse().unwrap()),
+                "case {}",
+                i
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn withdraw_missing() {
+        let mut daemon = daemon(
+            CONFIG,
+            vec![
+                published("up.example.net", "192.0.2.1", "example.net"),
+                published("gone.example.net", "192.0.2.1", "example.net"),
+            ],
+            "withdraw-missing",
+        )
+        .await;
+        daemon
+            .withdraw_missing(&["lo".to_string(), "eth0".to_string()])
+            .await;
+
+        let addr = |name| record(&daemon, name).records[name].addr;
+        assert_eq!(addr("up.example.net"), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(addr("gone.example.net"), None);
+    }
+
+    #[tokio::test]
+    async fn reload() {
+        let records = vec![
+            published("up.example.net", "192.0.2.1", "example.net"),
+            published("gone.example.net", "192.0.2.1", "example.net"),
+        ];
+        let mut daemon = daemon(CONFIG, records.clone(), "reload").await;
+        daemon.set_address("eth0", "192.0.2.1".parse().unwrap());
+        daemon.set_address("gone0", "192.0.2.1".parse().unwrap());
+
+        // Unchanged records keep their state
+        let (config, state_file) = with_state(CONFIG, records.clone(), "reload");
+        daemon.configure(config, false).await.unwrap();
+        std::fs::remove_file(state_file).unwrap();
+        assert!(!record(&daemon, "up.example.net").can_update());
+        assert!(!record(&daemon, "gone.example.net").can_update());
+
+        // A changed record is checked again even though server, zone
+        // and key are the same
+        let changed = CONFIG.replacen(
+            "interface = \"eth0\"",
+            "interface = \"eth0\"\nscope = \"192.0.2.0/24\"",
+            1,
+        );
+        let (config, state_file) = with_state(&changed, records, "reload");
+        daemon.configure(config, false).await.unwrap();
+        std::fs::remove_file(state_file).unwrap();
+        assert!(record(&daemon, "up.example.net").can_update());
+        assert!(!record(&daemon, "gone.example.net").can_update());
+    }
 }
@@ -1,24 +1,429 @@
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use hickory_client::proto::rr::{RData, RecordType};
+use hickory_client::proto::serialize::txt::RDataParser;
+use ifdyndnsd::config::{self, OneOrMore};
+use ifdyndnsd::{check, control, manual, state, status, AddressFamily, ConfigSource};
 use log::error;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Name of the key that is configured with command line arguments
+const QUICK_KEY: &str = "command-line";
+
+/// Watches interface addresses and updates DNS records like nsupdate
+#[derive(Parser)]
+#[command(
+    version,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    mode: Mode,
+    /// Query DNS but only log updates instead of sending them
+    #[arg(long, global = true)]
+    dry_run: bool,
+    /// With `--test`, skip what is only available on the host that
+    /// runs the service: secret and key files, `{machine-id-short}` and
+    /// `{env:VAR}`. For checking in a build sandbox.
+    #[arg(long, requires_all = ["test", "config"])]
+    offline: bool,
+    /// Configuration file, or directory of `*.toml` files
+    #[arg(required_unless_present = "server", conflicts_with = "quick")]
+    config: Option<String>,
+    #[command(flatten)]
+    quick: Quick,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Replace the records of a name with those given, per record type
+    Update(UpdateArgs),
+    /// Delete records, or whole record sets by type
+    Delete(DeleteArgs),
+    /// Check that the servers are reachable and authorize the keys for
+    /// all names, without changing records
+    Check {
+        /// Configuration file, or directory of `*.toml` files
+        config: String,
+    },
+    /// Show the addresses of all interfaces, which one each record
+    /// would be updated to and why, and what DNS currently has
+    Status {
+        /// Configuration file, or directory of `*.toml` files
+        config: String,
+        /// Keep printing address changes and how they affect records
+        #[arg(long)]
+        watch: bool,
+    },
+    /// Inspect and steer the running daemon through its control socket
+    Ctl {
+        /// Path of the control socket
+        #[arg(long, default_value = control::DEFAULT_PATH)]
+        socket: String,
+        /// Print the response as JSON
+        #[arg(long)]
+        json: bool,
+        #[command(subcommand)]
+        command: CtlCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum CtlCommand {
+    /// List all records with their address, last update and errors
+    List,
+    /// Send updates now, even if DNS already has the address
+    Update {
+        /// Only the record with this name [default: all]
+        name: Option<String>,
+    },
+    /// Query DNS now and update records that differ
+    Verify {
+        /// Only the record with this name [default: all]
+        name: Option<String>,
+    },
+    /// Stop sending updates, e.g. during maintenance of the server
+    Pause,
+    /// Send updates again
+    Resume,
+}
+
+impl From<CtlCommand> for control::Request {
+    fn from(command: CtlCommand) -> Self {
+        match command {
+            CtlCommand::List => control::Request::List,
+            CtlCommand::Update { name } => control::Request::Update { name },
+            CtlCommand::Verify { name } => control::Request::Verify { name },
+            CtlCommand::Pause => control::Request::Pause,
+            CtlCommand::Resume => control::Request::Resume,
+        }
+    }
+}
+
+/// Where to change records, with a key of the configuration
+#[derive(Args)]
+struct Target {
+    /// Configuration file, or directory of `*.toml` files
+    config: String,
+    /// Key to sign with, if the configuration has several
+    #[arg(long)]
+    key: Option<String>,
+    /// DNS name to change
+    #[arg(long)]
+    name: String,
+    /// Zone of the name [default: `default-zone` of the key or from
+    /// `[defaults]`]
+    #[arg(long)]
+    zone: Option<String>,
+}
+
+#[derive(Args)]
+#[group(id = "content", required = true, multiple = true)]
+struct Records {
+    /// Address of an A or AAAA record, can be repeated
+    #[arg(long = "addr")]
+    addrs: Vec<IpAddr>,
+    /// Record of any type in presentation format, e.g.
+    /// "MX 10 mail.example.net.", can be repeated
+    #[arg(long = "record", value_parser = parse_record)]
+    records: Vec<RData>,
+}
+
+#[derive(Args)]
+struct UpdateArgs {
+    #[command(flatten)]
+    target: Target,
+    /// Time to live of the records [default: `default-ttl` of the key,
+    /// from `[defaults]` or 0]
+    #[arg(long)]
+    ttl: Option<u32>,
+    #[command(flatten)]
+    records: Records,
+}
+
+#[derive(Args)]
+struct DeleteArgs {
+    #[command(flatten)]
+    target: Target,
+    /// Type of a whole record set to delete, can be repeated
+    #[arg(long = "type", value_parser = parse_type, group = "content")]
+    types: Vec<RecordType>,
+    #[command(flatten)]
+    records: Records,
+}
+
+/// Parse a record type in any case, hickory only accepts upper case
+fn parse_type(s: &str) -> Result<RecordType, String> {
+    RecordType::from_str(&s.to_ascii_uppercase()).map_err(|e| e.to_string())
+}
+
+/// Parse a record like "MX 10 mail.example.net."
+fn parse_record(s: &str) -> Result<RData, String> {
+    let (record_type, rdata) = s.trim().split_once(char::is_whitespace).unwrap_or((s, ""));
+    let record_type = parse_type(record_type)?;
+    RData::try_from_str(record_type, rdata.trim()).map_err(|e| e.to_string())
+}
+
+impl Target {
+    fn change(self, ttl: Option<u32>, records: Records, types: Vec<RecordType>) -> manual::Change {
+        let addrs = records.addrs.into_iter().map(RData::from);
+        manual::Change {
+            key: self.key,
+            name: self.name,
+            zone: self.zone,
+            ttl,
+            records: addrs.chain(records.records).collect(),
+            types,
+        }
+    }
+}
+
+/// What to do instead of running until stopped
+#[derive(Args)]
+#[group(multiple = false)]
+struct Mode {
+    /// Check the configuration and exit
+    #[arg(long)]
+    test: bool,
+    /// Update all records that are out of date, print a summary and exit
+    #[arg(long)]
+    once: bool,
+    /// Print the configuration with `[[a]]` and `[[aaaa]]` rewritten as
+    /// `[[record]]`
+    #[arg(long, requires = "config", conflicts_with = "quick")]
+    convert: bool,
+}
+
+/// A single record that is configured without a file
+#[derive(Args)]
+#[group(id = "quick", multiple = true)]
+struct Quick {
+    /// DNS server to send updates to
+    #[arg(long, requires_all = ["interface", "names"])]
+    server: Option<IpAddr>,
+    /// Name of the TSIG key
+    #[arg(long, requires = "server")]
+    key_name: Option<String>,
+    /// TSIG algorithm: hmac-sha256, hmac-sha384 or hmac-sha512
+    #[arg(long, requires = "server")]
+    alg: Option<String>,
+    /// BIND key file, e.g. from `ddns-confgen` or `tsig-keygen`
+    #[arg(long, requires = "server")]
+    key_file: Option<String>,
+    /// File with the raw secret
+    #[arg(long, requires = "server")]
+    secret_file: Option<String>,
+    /// File with the base64 encoded secret
+    #[arg(long, requires = "server")]
+    secret_file_base64: Option<String>,
+    /// Network interface to watch for addresses
+    #[arg(long, requires = "server")]
+    interface: Option<String>,
+    /// DNS name to update, can be repeated
+    #[arg(long = "name", requires = "server")]
+    names: Vec<String>,
+    #[arg(long, requires = "server")]
+    zone: Option<String>,
+    /// Address family to update, can be repeated [default: both]
+    #[arg(long = "family", value_enum, requires = "server")]
+    families: Vec<Family>,
+    /// Time to live of the records [default: 0]
+    #[arg(long, requires = "server")]
+    ttl: Option<u32>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+impl Quick {
+    fn config(self, server: IpAddr) -> config::Config {
+        let mut key = config::TsigKey::new(server);
+        key.name = self.key_name;
+        key.alg = self.alg;
+        key.key_file = self.key_file;
+        key.secret_file = self.secret_file.map(OneOrMore::One);
+        key.secret_file_base64 = self.secret_file_base64.map(OneOrMore::One);
+
+        let families = self
+            .families
+            .iter()
+            .map(|family| match family {
+                Family::Ipv4 => AddressFamily::IPv4,
+                Family::Ipv6 => AddressFamily::IPv6,
+            })
+            .collect::<Vec<_>>();
+        let record = config::Record {
+            key: QUICK_KEY.to_string(),
+            names: Some(OneOrMore::More(self.names)),
+            interface: self.interface.unwrap_or_default(),
+            zone: self.zone,
+            ttl: self.ttl,
+            families: Some(families).filter(|families| !families.is_empty()),
+            ipv4: None,
+            ipv6: None,
+            neighbors: None,
+            withdraw_on_exit: None,
+        };
+
+        config::Config {
+            keys: HashMap::from([(QUICK_KEY.to_string(), key)]),
+            record: Some(vec![record]),
+            ..config::Config::default()
+        }
+    }
+}
 
 #[tokio::main]
-async fn main() -> Result<(), String> {
+async fn main() {
     let env = env_logger::Env::default().filter_or("RUST_LOG", "info");
 
     env_logger::init_from_env(env);
 
-    let args = std::env::args().collect::<Vec<_>>();
-    match &args[1..] {
-        [command, config_file] if command == "--test" => {
-            ifdyndnsd::config::load(config_file).unwrap();
-            Ok(())
+    let cli = Cli::parse();
+    let options = ifdyndnsd::Options {
+        dry_run: cli.dry_run,
+    };
+    let result = match cli.command {
+        Some(Command::Update(args)) => {
+            let config_file = args.target.config.clone();
+            let change = args.target.change(args.ttl, args.records, vec![]);
+            manual::update(&config_file, change, options).await
+        }
+        Some(Command::Delete(args)) => {
+            let config_file = args.target.config.clone();
+            let change = args.target.change(None, args.records, args.types);
+            manual::delete(&config_file, change, options).await
+        }
+        Some(Command::Check { config }) => match check::check(&config).await {
+            Ok(true) => Ok(()),
+            Ok(false) => std::process::exit(1),
+            Err(e) => Err(e),
+        },
+        Some(Command::Status { config, watch }) => status::status(&config, watch).await,
+        Some(Command::Ctl {
+            socket,
+            json,
+            command,
+        }) => ctl(&socket, json, command.into()).await,
+        None => run(&cli.mode, cli.offline, cli.config, cli.quick, options).await,
+    };
+    if let Err(e) = result {
+        error!("{e}");
+        std::process::exit(1);
+    }
+}
+
+/// Run with a configuration file or quick mode arguments
+async fn run(
+    mode: &Mode,
+    offline: bool,
+    config: Option<String>,
+    quick: Quick,
+    options: ifdyndnsd::Options,
+) -> Result<(), ifdyndnsd::Error> {
+    let source = match (config, quick.server) {
+        (Some(config_file), _) => ConfigSource::File(config_file),
+        (None, Some(server)) => ConfigSource::Config(Box::new(quick.config(server))),
+        // Ruled out by `required_unless_present`
+        (None, None) => unreachable!(),
+    };
+
+    match source {
+        ConfigSource::File(config_file) if mode.convert => match config::convert(&config_file) {
+            Ok(converted) => {
+                print!("{converted}");
+                Ok(())
+            }
+            Err(e) => Err(report(e)),
+        },
+        source if mode.test => {
+            let result = match source {
+                ConfigSource::File(config_file) if offline => config::load_offline(&config_file),
+                ConfigSource::File(config_file) => config::load(&config_file),
+                ConfigSource::Config(config) => config::validate(*config),
+            };
+            result.map(drop).map_err(report)
+        }
+        source if mode.once => match ifdyndnsd::run_once(source, options).await {
+            Ok(true) => Ok(()),
+            Ok(false) => std::process::exit(1),
+            Err(e) => Err(e),
+        },
+        source => ifdyndnsd::run(source, options).await,
+    }
+}
+
+/// Send `request` to the daemon and print its response
+async fn ctl(socket: &str, json: bool, request: control::Request) -> Result<(), ifdyndnsd::Error> {
+    let response = control::send(socket, &request).await?;
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&response).map_err(control::Error::from)?
+        );
+    } else {
+        let now = state::now();
+        for record in &response.records {
+            let mut details = vec![match record.address {
+                Some(addr) => addr.to_string(),
+                None => "no address".to_string(),
+            }];
+            if let Some(published) = record.published {
+                details.push(format!("published {published}"));
+            }
+            if let Some(last_update) = record.last_update {
+                let ago = Duration::from_secs(now.saturating_sub(last_update));
+                details.push(format!("updated {ago:?} ago"));
+            }
+            if let Some(next_retry) = record.next_retry {
+                let delay = Duration::from_secs(next_retry.saturating_sub(now));
+                details.push(format!("retry in {delay:?}"));
+            }
+            if let Some(e) = &record.last_error {
+                details.push(format!("error: {e}"));
+            }
+            println!(
+                "{} {} on {}: {}",
+                record.name,
+                record.record_type,
+                record.interface,
+                details.join(", ")
+            );
+        }
+        if response.paused {
+            println!("Updates are paused");
         }
-        [config_file] => {
-            ifdyndnsd::run(config_file).await.unwrap();
-            panic!("ifdyndnsd exited");
+    }
+    match response.error {
+        Some(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+        None => Ok(()),
+    }
+}
+
+/// Log the problems of an invalid configuration one by one and exit
+fn report(e: config::Error) -> ifdyndnsd::Error {
+    match e {
+        config::Error::Invalid(problems) => {
+            for problem in problems.0 {
+                error!("{problem}");
+            }
+            std::process::exit(1);
         }
-        _ => {
-            error!("Usage: {} [--test] <config.toml>", args[0]);
+        config::Error::Parse { problem, .. } => {
+            error!("{problem}");
             std::process::exit(1);
         }
+        e => e.into(),
     }
 }
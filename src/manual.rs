@@ -0,0 +1,127 @@
+//! One-off changes with the keys of the configuration, like `nsupdate`
+
+use crate::{config, dns, Error, Options};
+use hickory_client::proto::rr::{record_type::RecordType, RData};
+
+/// Records to change at one name
+pub struct Change {
+    /// Key of the configuration, can be omitted if there is only one
+    pub key: Option<String>,
+    pub name: String,
+    /// Defaults to the key's `default-zone` and then `[defaults]`
+    pub zone: Option<String>,
+    /// Defaults to the key's `default-ttl` and then `[defaults]`
+    pub ttl: Option<u32>,
+    /// Records to add or delete, of any types
+    pub records: Vec<RData>,
+    /// Whole record sets to delete
+    pub types: Vec<RecordType>,
+}
+
+/// Replace the record sets of each type in `change.records` with
+/// those records
+///
+/// # Errors
+///
+/// Will return `Err` if the configuration cannot be loaded, the key
+/// cannot be set up, or an update fails.
+pub async fn update(config_file: &str, change: Change, options: Options) -> Result<(), Error> {
+    let (mut server, zone, ttl) = connect(config_file, &change, options).await?;
+    let Change { name, records, .. } = change;
+    for rdatas in by_type(records) {
+        server
+            .replace(&name, zone.as_deref(), ttl, rdatas)
+            .await
+            .map_err(|source| Error::Update {
+                name: name.clone(),
+                source,
+            })?;
+    }
+    Ok(())
+}
+
+/// Delete the record sets of `change.types` and the single records of
+/// `change.records`
+///
+/// # Errors
+///
+/// Will return `Err` if the configuration cannot be loaded, the key
+/// cannot be set up, or a deletion fails.
+pub async fn delete(config_file: &str, change: Change, options: Options) -> Result<(), Error> {
+    let (mut server, zone, _) = connect(config_file, &change, options).await?;
+    let Change {
+        name,
+        records,
+        types,
+        ..
+    } = change;
+    let update_error = |source| Error::Update {
+        name: name.clone(),
+        source,
+    };
+    for record_type in types {
+        server
+            .delete(&name, record_type, zone.as_deref())
+            .await
+            .map_err(update_error)?;
+    }
+    for rdatas in by_type(records) {
+        server
+            .delete_records(&name, zone.as_deref(), rdatas)
+            .await
+            .map_err(update_error)?;
+    }
+    Ok(())
+}
+
+/// Set up the key of `change`, and look up zone and TTL
+async fn connect(
+    config_file: &str,
+    change: &Change,
+    options: Options,
+) -> Result<(dns::Server, Option<String>, u32), Error> {
+    let config = config::load(config_file)?;
+    let (name, key) = if let Some(name) = &change.key {
+        config
+            .keys
+            .get_key_value(name)
+            .ok_or_else(|| Error::NoSuchKey(name.clone()))?
+    } else {
+        let mut keys = config.keys.iter();
+        match (keys.next(), keys.next()) {
+            (Some(key), None) => key,
+            _ => return Err(Error::KeyRequired(config.keys.len())),
+        }
+    };
+    let defaults = config.defaults.clone().unwrap_or_default();
+    let zone = change
+        .zone
+        .clone()
+        .or_else(|| key.default_zone.clone())
+        .or(defaults.zone);
+    let ttl = change.ttl.or(key.default_ttl).or(defaults.ttl).unwrap_or(0);
+
+    let mut server = dns::Server::new(key.server, key)
+        .await
+        .map_err(|source| Error::Server {
+            key: name.clone(),
+            source,
+        })?;
+    server.set_dry_run(options.dry_run);
+    Ok((server, zone, ttl))
+}
+
+/// Group records by type, in the order of their first appearance
+fn by_type(records: Vec<RData>) -> Vec<Vec<RData>> {
+    let mut groups: Vec<Vec<RData>> = vec![];
+    for rdata in records {
+        match groups
+            .iter_mut()
+            .find(|group| group[0].record_type() == rdata.record_type())
+        {
+            Some(group) => group.push(rdata),
+            None => groups.push(vec![rdata]),
+        }
+    }
+    groups
+}
@@ -0,0 +1,422 @@
+//! Prometheus metrics, served over HTTP in the text exposition format
+
+use crate::control::RecordStatus;
+use crate::retry::ErrorClass;
+use crate::state;
+use log::{debug, error};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::spawn;
+use tokio::time::timeout;
+
+/// Upper bounds of the DNS query latency histogram, in seconds
+const QUERY_BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// Time that a client has to send its request header
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Longest request header that is read
+const MAX_HEADER_SIZE: usize = 16 * 1024;
+
+static METRICS: Metrics = Metrics {
+    netlink_events: AtomicU64::new(0),
+    netlink_restarts: AtomicU64::new(0),
+    queries: Mutex::new(Histogram {
+        counts: [0; QUERY_BUCKETS.len()],
+        count: 0,
+        sum: 0.0,
+    }),
+    records: Mutex::new(BTreeMap::new()),
+};
+
+struct Metrics {
+    netlink_events: AtomicU64,
+    netlink_restarts: AtomicU64,
+    queries: Mutex<Histogram>,
+    /// By name and record type
+    records: Mutex<BTreeMap<(String, String), Record>>,
+}
+
+struct Histogram {
+    /// Observations per bucket, not cumulative
+    counts: [u64; QUERY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    /// Cumulative buckets, sum and count
+    fn samples(&self) -> Vec<(String, String)> {
+        let mut cumulative = 0;
+        let mut samples = vec![];
+        for (le, count) in QUERY_BUCKETS.iter().zip(self.counts) {
+            cumulative += count;
+            samples.push((format!("_bucket{{le=\"{le}\"}}"), cumulative.to_string()));
+        }
+        samples.push(("_bucket{le=\"+Inf\"}".to_string(), self.count.to_string()));
+        samples.push(("_sum".to_string(), self.sum.to_string()));
+        samples.push(("_count".to_string(), self.count.to_string()));
+        samples
+    }
+}
+
+#[derive(Default)]
+struct Record {
+    attempted: u64,
+    succeeded: u64,
+    /// By error class
+    failed: BTreeMap<String, u64>,
+    /// Unix time
+    last_success: Option<u64>,
+    /// `None` while there is no address to publish
+    in_sync: Option<bool>,
+}
+
+/// Metrics stay usable even if a thread has panicked while holding
+/// the lock
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn with_record(name: &str, record_type: &str, f: impl FnOnce(&mut Record)) {
+    let mut records = lock(&METRICS.records);
+    f(records
+        .entry((name.to_string(), record_type.to_string()))
+        .or_default());
+}
+
+pub fn netlink_event() {
+    METRICS.netlink_events.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn netlink_restart() {
+    METRICS.netlink_restarts.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn query_duration(duration: Duration) {
+    let seconds = duration.as_secs_f64();
+    let mut queries = lock(&METRICS.queries);
+    if let Some(bucket) = QUERY_BUCKETS.iter().position(|le| seconds <= *le) {
+        queries.counts[bucket] += 1;
+    }
+    queries.count += 1;
+    queries.sum += seconds;
+}
+
+/// An update is sent because DNS differs
+pub fn update_attempted(name: &str, record_type: &str) {
+    with_record(name, record_type, |record| record.attempted += 1);
+}
+
+pub fn update_result(name: &str, record_type: &str, result: Result<(), ErrorClass>) {
+    with_record(name, record_type, |record| match result {
+        Ok(()) => record.succeeded += 1,
+        Err(class) => *record.failed.entry(class.to_string()).or_default() += 1,
+    });
+}
+
+/// Take the last successful update and whether DNS is in sync from
+/// the state of all records
+pub fn set_records(statuses: &[RecordStatus]) {
+    let mut records = lock(&METRICS.records);
+    for record in records.values_mut() {
+        record.in_sync = None;
+    }
+    for status in statuses {
+        let record = records
+            .entry((status.name.clone(), status.record_type.clone()))
+            .or_default();
+        record.last_success = status.last_update;
+        record.in_sync = status
+            .address
+            .map(|address| status.published == Some(address));
+    }
+}
+
+/// All metrics in the text exposition format
+#[must_use]
+pub fn render() -> String {
+    let mut lines = vec![];
+    let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+        lines.push(format!("# HELP ifdyndnsd_{name} {help}"));
+        lines.push(format!("# TYPE ifdyndnsd_{name} {kind}"));
+        for (labels, value) in samples {
+            lines.push(format!("ifdyndnsd_{name}{labels} {value}"));
+        }
+    };
+
+    metric(
+        "netlink_events_total",
+        "counter",
+        "Netlink messages received",
+        vec![(
+            String::new(),
+            METRICS.netlink_events.load(Ordering::Relaxed).to_string(),
+        )],
+    );
+    metric(
+        "netlink_restarts_total",
+        "counter",
+        "Restarts of the netlink listener",
+        vec![(
+            String::new(),
+            METRICS.netlink_restarts.load(Ordering::Relaxed).to_string(),
+        )],
+    );
+
+    metric(
+        "dns_query_duration_seconds",
+        "histogram",
+        "Latency of DNS queries",
+        lock(&METRICS.queries).samples(),
+    );
+
+    let records = lock(&METRICS.records);
+    let labels = |(name, record_type): &(String, String)| {
+        format!("name=\"{}\",type=\"{record_type}\"", escape(name))
+    };
+    let per_record = |value: fn(&Record) -> Option<u64>| {
+        records
+            .iter()
+            .filter_map(|(key, record)| {
+                Some((format!("{{{}}}", labels(key)), value(record)?.to_string()))
+            })
+            .collect()
+    };
+    metric(
+        "updates_attempted_total",
+        "counter",
+        "Updates sent because DNS differed",
+        per_record(|record| Some(record.attempted)),
+    );
+    metric(
+        "updates_succeeded_total",
+        "counter",
+        "Updates accepted by the server",
+        per_record(|record| Some(record.succeeded)),
+    );
+    metric(
+        "updates_failed_total",
+        "counter",
+        "Failed updates by error class",
+        records
+            .iter()
+            .flat_map(|(key, record)| {
+                record.failed.iter().map(move |(class, failed)| {
+                    (
+                        format!("{{{},class=\"{class}\"}}", labels(key)),
+                        failed.to_string(),
+                    )
+                })
+            })
+            .collect(),
+    );
+    let now = state::now();
+    metric(
+        "last_success_age_seconds",
+        "gauge",
+        "Seconds since the last successful update",
+        records
+            .iter()
+            .filter_map(|(key, record)| {
+                let age = now.saturating_sub(record.last_success?);
+                Some((format!("{{{}}}", labels(key)), age.to_string()))
+            })
+            .collect(),
+    );
+    metric(
+        "in_sync",
+        "gauge",
+        "Whether the published address is the one on the interface",
+        per_record(|record| record.in_sync.map(u64::from)),
+    );
+
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serve the metrics on `addr` for any HTTP request
+///
+/// # Errors
+///
+/// Will return `Err` if `addr` cannot be listened on.
+pub async fn listen(addr: SocketAddr) -> Result<(), io::Error> {
+    let listener = TcpListener::bind(addr).await?;
+    spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, peer)) => {
+                    debug!("Metrics request from {peer}");
+                    spawn(async move {
+                        if let Err(e) = serve(stream).await {
+                            debug!("Metrics request from {peer}: {e}");
+                        }
+                    });
+                }
+                Err(e) => error!("Metrics listener: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn serve(mut stream: TcpStream) -> Result<(), io::Error> {
+    let complete = timeout(REQUEST_TIMEOUT, read_header(&mut stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    if !complete {
+        return Ok(());
+    }
+
+    let body = render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read the request header, whose content doesn't matter. Returns
+/// whether it is complete.
+async fn read_header(stream: &mut TcpStream) -> Result<bool, io::Error> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        let len = stream.read(&mut buf).await?;
+        if len == 0 || request.len() > MAX_HEADER_SIZE {
+            return Ok(false);
+        }
+        request.extend_from_slice(&buf[..len]);
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records() {
+        // Metrics are global, so the names are only used here
+        let name = "render.example.net";
+        update_attempted(name, "A");
+        update_result(name, "A", Ok(()));
+        update_attempted(name, "A");
+        update_result(name, "A", Err(ErrorClass::ServFail));
+        let status = |name: &str, published| RecordStatus {
+            name: name.to_string(),
+            record_type: "AAAA".to_string(),
+            interface: "ppp0".to_string(),
+            address: Some("2001:db8::1".parse().unwrap()),
+            published,
+            last_update: Some(state::now() - 5),
+            last_error: None,
+            next_retry: None,
+        };
+        let quoted = "render\"quoted\\.example.net";
+        set_records(&[
+            status(name, None),
+            status(quoted, Some("2001:db8::1".parse().unwrap())),
+        ]);
+
+        let rendered = render();
+        let lines = rendered.lines().collect::<Vec<_>>();
+        for expected in [
+            "# TYPE ifdyndnsd_updates_attempted_total counter",
+            r#"ifdyndnsd_updates_attempted_total{name="render.example.net",type="A"} 2"#,
+            r#"ifdyndnsd_updates_succeeded_total{name="render.example.net",type="A"} 1"#,
+            r#"ifdyndnsd_updates_failed_total{name="render.example.net",type="A",class="servfail"} 1"#,
+            r#"ifdyndnsd_in_sync{name="render.example.net",type="AAAA"} 0"#,
+            r#"ifdyndnsd_in_sync{name="render\"quoted\\.example.net",type="AAAA"} 1"#,
+        ] {
+            assert!(
+                lines.contains(&expected),
+                "{} missing in\n{}",
+                expected,
+                rendered
+            );
+        }
+        let age = lines
+            .iter()
+            .find_map(|line| {
+                line.strip_prefix(
+                    r#"ifdyndnsd_last_success_age_seconds{name="render.example.net",type="AAAA"} "#,
+                )
+            })
+            .unwrap();
+        assert!(["5", "6"].contains(&age));
+        // No address to publish yet
+        assert!(!rendered.contains(r#"ifdyndnsd_in_sync{name="render.example.net",type="A"}"#));
+        assert!(rendered.ends_with('\n'));
+    }
+
+    #[test]
+    fn histogram() {
+        let mut histogram = Histogram {
+            counts: [0; QUERY_BUCKETS.len()],
+            count: 3,
+            sum: 10.5,
+        };
+        histogram.counts[1] = 1;
+        histogram.counts[4] = 1;
+        let samples = histogram.samples();
+        assert_eq!(
+            samples[0],
+            (r#"_bucket{le="0.005"}"#.to_string(), "0".to_string())
+        );
+        assert_eq!(
+            samples[1],
+            (r#"_bucket{le="0.01"}"#.to_string(), "1".to_string())
+        );
+        assert_eq!(
+            samples[4],
+            (r#"_bucket{le="0.1"}"#.to_string(), "2".to_string())
+        );
+        assert_eq!(
+            samples[9],
+            (r#"_bucket{le="5"}"#.to_string(), "2".to_string())
+        );
+        assert_eq!(
+            samples[10..],
+            [
+                (r#"_bucket{le="+Inf"}"#.to_string(), "3".to_string()),
+                ("_sum".to_string(), "10.5".to_string()),
+                ("_count".to_string(), "3".to_string()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        let server = spawn(serve(stream));
+
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("\r\n\r\n# HELP ifdyndnsd_netlink_events_total"));
+    }
+}
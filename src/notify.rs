@@ -0,0 +1,43 @@
+//! Service notifications for systemd with `Type=notify`, see
+//! `sd_notify(3)`. Without `$NOTIFY_SOCKET` they are not sent.
+
+use log::warn;
+use sd_notify::NotifyState;
+use std::time::Duration;
+
+fn notify(states: &[NotifyState<'_>]) {
+    if let Err(e) = sd_notify::notify(false, states) {
+        warn!("Cannot notify systemd: {e}");
+    }
+}
+
+/// Startup is complete
+pub fn ready(status: &str) {
+    notify(&[NotifyState::Ready, NotifyState::Status(status)]);
+}
+
+/// A summary that `systemctl status` shows
+pub fn status(status: &str) {
+    notify(&[NotifyState::Status(status)]);
+}
+
+pub fn stopping() {
+    notify(&[NotifyState::Stopping]);
+}
+
+/// Tell the watchdog that we are still alive
+pub fn watchdog() {
+    notify(&[NotifyState::Watchdog]);
+}
+
+/// How often to ping the watchdog: half of `WatchdogSec=`, or `None`
+/// if it is not enabled
+#[must_use]
+pub fn watchdog_interval() -> Option<Duration> {
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(false, &mut usec) {
+        Some(Duration::from_micros(usec) / 2)
+    } else {
+        None
+    }
+}
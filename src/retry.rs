@@ -0,0 +1,228 @@
+use crate::config;
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+
+pub const DEFAULT_INITIAL_INTERVAL: u64 = 30;
+pub const DEFAULT_MAX_INTERVAL: u64 = 3600;
+pub const DEFAULT_MULTIPLIER: f64 = 2.0;
+pub const DEFAULT_JITTER: f64 = 0.1;
+
+/// Failure classes that are retried differently
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorClass {
+    /// Timeout or no valid response
+    Network,
+    /// SERVFAIL
+    ServFail,
+    /// REFUSED, usually a missing `update-policy` grant
+    Refused,
+    /// NOTAUTH, BADKEY, BADSIG or BADTIME: the server doesn't accept our key
+    NotAuth,
+    /// NOTZONE: the name is not inside the zone
+    NotZone,
+    /// Any other error response
+    Other,
+}
+
+impl ErrorClass {
+    /// Transient failures are retried with exponential backoff. All
+    /// others are considered permanent until the configuration (here
+    /// or on the server) changes, and are only retried at the maximum
+    /// interval.
+    #[must_use]
+    pub fn is_transient(self) -> bool {
+        matches!(self, ErrorClass::Network | ErrorClass::ServFail)
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ErrorClass::Network => "network",
+            ErrorClass::ServFail => "servfail",
+            ErrorClass::Refused => "refused",
+            ErrorClass::NotAuth => "notauth",
+            ErrorClass::NotZone => "notzone",
+            ErrorClass::Other => "other",
+        };
+        f.write_str(s)
+    }
+}
+
+/// Invalid `[retry]` settings
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("initial-interval must be between 1 and max-interval ({max_interval}s)")]
+    InitialInterval { max_interval: u64 },
+    #[error("multiplier must be at least 1")]
+    Multiplier,
+    #[error("jitter must be at least 0 and less than 1")]
+    Jitter,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    initial_interval: Duration,
+    max_interval: Duration,
+    multiplier: f64,
+    jitter: f64,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Policy {
+            initial_interval: Duration::from_secs(DEFAULT_INITIAL_INTERVAL),
+            max_interval: Duration::from_secs(DEFAULT_MAX_INTERVAL),
+            multiplier: DEFAULT_MULTIPLIER,
+            jitter: DEFAULT_JITTER,
+        }
+    }
+}
+
+impl Policy {
+    /// # Errors
+    ///
+    /// Will return `Err` if
+    ///
+    /// - `initial-interval` is 0 or larger than `max-interval`.
+    /// - `multiplier` is smaller than 1.
+    /// - `jitter` is not at least 0 and less than 1.
+    ///
+    pub fn new(config: Option<&config::Retry>) -> Result<Self, Error> {
+        let mut policy = Policy::default();
+        let Some(config) = config else {
+            return Ok(policy);
+        };
+
+        if let Some(initial_interval) = config.initial_interval {
+            policy.initial_interval = Duration::from_secs(initial_interval);
+        }
+        if let Some(max_interval) = config.max_interval {
+            policy.max_interval = Duration::from_secs(max_interval);
+        }
+        if let Some(multiplier) = config.multiplier {
+            policy.multiplier = multiplier;
+        }
+        if let Some(jitter) = config.jitter {
+            policy.jitter = jitter;
+        }
+
+        if policy.initial_interval.is_zero() || policy.initial_interval > policy.max_interval {
+            return Err(Error::InitialInterval {
+                max_interval: policy.max_interval.as_secs(),
+            });
+        }
+        if policy.multiplier.is_nan() || policy.multiplier < 1.0 {
+            return Err(Error::Multiplier);
+        }
+        // With a jitter of 1, the delay could be 0
+        if !(0.0..1.0).contains(&policy.jitter) {
+            return Err(Error::Jitter);
+        }
+        Ok(policy)
+    }
+
+    /// Time to wait after the `failures`th consecutive failure, never
+    /// more than `max-interval`
+    #[must_use]
+    pub fn delay(&self, class: ErrorClass, failures: u32) -> Duration {
+        let delay = if class.is_transient() {
+            let exponent = i32::try_from(failures.saturating_sub(1)).unwrap_or(i32::MAX);
+            Duration::try_from_secs_f64(
+                self.initial_interval.as_secs_f64() * self.multiplier.powi(exponent),
+            )
+            .map_or(self.max_interval, |delay| delay.min(self.max_interval))
+        } else {
+            self.max_interval
+        };
+
+        let factor = 1.0 + rand::random_range(-self.jitter..=self.jitter);
+        delay.mul_f64(factor).min(self.max_interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f64) -> Policy {
+        Policy::new(Some(&config::Retry {
+            initial_interval: Some(10),
+            max_interval: Some(100),
+            multiplier: Some(3.0),
+            jitter: Some(jitter),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn transient_backoff() {
+        let policy = policy(0.0);
+        let delays = (1..=5)
+            .map(|failures| policy.delay(ErrorClass::Network, failures).as_secs())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, [10, 30, 90, 100, 100]);
+        assert_eq!(
+            policy.delay(ErrorClass::ServFail, u32::MAX),
+            Duration::from_secs(100)
+        );
+    }
+
+    #[test]
+    fn permanent_at_max_interval() {
+        let policy = policy(0.0);
+        for class in [ErrorClass::Refused, ErrorClass::NotAuth, ErrorClass::Other] {
+            assert_eq!(policy.delay(class, 1), Duration::from_secs(100));
+        }
+    }
+
+    #[test]
+    fn jitter() {
+        let policy = policy(0.5);
+        for _ in 0..100 {
+            let delay = policy.delay(ErrorClass::Network, 1);
+            assert!(delay >= Duration::from_secs(5) && delay <= Duration::from_secs(15));
+        }
+    }
+
+    #[test]
+    fn jitter_below_max_interval() {
+        let policy = policy(0.5);
+        for _ in 0..100 {
+            let delay = policy.delay(ErrorClass::Refused, 1);
+            assert!(delay >= Duration::from_secs(50) && delay <= Duration::from_secs(100));
+            assert!(policy.delay(ErrorClass::Network, 10) <= Duration::from_secs(100));
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let retry = |initial_interval, multiplier, jitter| config::Retry {
+            initial_interval: Some(initial_interval),
+            max_interval: Some(100),
+            multiplier: Some(multiplier),
+            jitter: Some(jitter),
+        };
+        assert!(matches!(
+            Policy::new(Some(&retry(0, 2.0, 0.1))),
+            Err(Error::InitialInterval { max_interval: 100 })
+        ));
+        assert!(matches!(
+            Policy::new(Some(&retry(101, 2.0, 0.1))),
+            Err(Error::InitialInterval { .. })
+        ));
+        assert!(matches!(
+            Policy::new(Some(&retry(10, 0.5, 0.1))),
+            Err(Error::Multiplier)
+        ));
+        assert!(matches!(
+            Policy::new(Some(&retry(10, 2.0, 1.0))),
+            Err(Error::Jitter)
+        ));
+        assert!(matches!(
+            Policy::new(Some(&retry(10, 2.0, -0.1))),
+            Err(Error::Jitter)
+        ));
+    }
+}
@@ -0,0 +1,103 @@
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+use std::fmt;
+
+/// Key material that doesn't show up in logs: `Debug` and `Display`
+/// are redacted, and the memory is overwritten when dropped.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    #[must_use]
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Secret {
+    fn from(bytes: Vec<u8>) -> Self {
+        Secret(bytes)
+    }
+}
+
+impl From<String> for Secret {
+    fn from(s: String) -> Self {
+        Secret(s.into_bytes())
+    }
+}
+
+impl Drop for Secret {
+    fn drop(&mut self) {
+        self.0.fill(0);
+        // Keep the compiler from eliding the writes to memory that is
+        // about to be freed
+        std::hint::black_box(&mut self.0);
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SecretVisitor;
+
+        impl Visitor<'_> for SecretVisitor {
+            type Value = Secret;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a string")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Secret, E> {
+                Ok(Secret(s.as_bytes().to_vec()))
+            }
+
+            fn visit_string<E: de::Error>(self, s: String) -> Result<Secret, E> {
+                Ok(s.into())
+            }
+        }
+
+        deserializer.deserialize_string(SecretVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacted() {
+        let secret = Secret::from("topsecret".to_string());
+        assert_eq!(format!("{secret:?}"), "Secret(<redacted>)");
+        assert_eq!(secret.to_string(), "<redacted>");
+        assert_eq!(secret.expose(), b"topsecret");
+    }
+
+    #[test]
+    fn redacted_in_config() {
+        let config: crate::config::Config = toml::from_str(
+            r#"
+[keys.k]
+server = "127.0.0.1"
+name = "k"
+alg = "hmac-sha256"
+secret = "topsecret"
+secret-base64 = "dG9wc2VjcmV0"
+"#,
+        )
+        .unwrap();
+        let debug = format!("{config:?}");
+        assert!(!debug.contains("topsecret") && !debug.contains("dG9wc2VjcmV0"));
+        assert!(debug.contains("<redacted>"));
+    }
+}
@@ -0,0 +1,159 @@
+use hickory_client::proto::rr::record_type::RecordType;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::{rename, File};
+use std::io::{Read, Write};
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// What has been published, so that a restart doesn't need to
+/// query and update everything again
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct State {
+    #[serde(default)]
+    pub records: BTreeMap<String, Record>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Record {
+    /// Last successfully published address
+    pub addr: Option<IpAddr>,
+    pub ttl: u32,
+    /// Unix time of the last attempt
+    pub time: u64,
+    #[serde(rename = "last-success")]
+    pub last_success: Option<u64>,
+    /// Error of the last attempt if it failed
+    pub error: Option<String>,
+    #[serde(default)]
+    pub failures: u32,
+    /// Unix time of the next retry
+    #[serde(rename = "retry-at")]
+    pub retry_at: Option<u64>,
+    /// Server, zone and key that `addr` has been published with, so
+    /// that a record is published again when it moves
+    pub server: Option<IpAddr>,
+    pub zone: Option<String>,
+    pub key: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Parse(#[from] toml::de::Error),
+    #[error(transparent)]
+    Serialize(#[from] toml::ser::Error),
+}
+
+#[must_use]
+pub fn key(name: &str, record_type: RecordType) -> String {
+    format!("{name} {record_type}")
+}
+
+/// Current Unix time
+#[must_use]
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs())
+}
+
+#[must_use]
+pub fn to_unix_time(instant: Instant) -> u64 {
+    now() + instant.saturating_duration_since(Instant::now()).as_secs()
+}
+
+#[must_use]
+pub fn from_unix_time(time: u64) -> Instant {
+    Instant::now() + Duration::from_secs(time.saturating_sub(now()))
+}
+
+/// Default location when running with systemd's `StateDirectory=`
+#[must_use]
+pub fn default_path() -> Option<String> {
+    let state_directory = std::env::var("STATE_DIRECTORY").ok()?;
+    let state_directory = state_directory.split(':').next()?;
+    Some(format!("{state_directory}/state.toml"))
+}
+
+/// # Errors
+///
+/// Will return `Err` if `filename` cannot be read or parsed.
+pub fn load(filename: &str) -> Result<State, Error> {
+    let mut f = File::open(filename)?;
+    let mut buf = String::new();
+    f.read_to_string(&mut buf)?;
+    let state = toml::from_str(&buf)?;
+    Ok(state)
+}
+
+/// Replaces `filename` atomically
+///
+/// # Errors
+///
+/// Will return `Err` if the file cannot be written.
+pub fn save(filename: &str, state: &State) -> Result<(), Error> {
+    let buf = toml::to_string(state)?;
+    let tmp_filename = format!("{filename}.tmp");
+    let mut f = File::create(&tmp_filename)?;
+    f.write_all(buf.as_bytes())?;
+    f.sync_all()?;
+    rename(&tmp_filename, filename)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let filename =
+            std::env::temp_dir().join(format!("ifdyndnsd-state-{}.toml", std::process::id()));
+        let filename = filename.to_str().unwrap();
+        let mut state = State::default();
+        state.records.insert(
+            key("host.example.net", RecordType::A),
+            Record {
+                addr: Some("192.0.2.1".parse().unwrap()),
+                ttl: 60,
+                time: 1_700_000_000,
+                last_success: Some(1_700_000_000),
+                failures: 2,
+                retry_at: Some(1_700_000_100),
+                server: Some("127.0.0.1".parse().unwrap()),
+                zone: Some("example.net".to_string()),
+                key: Some("k".to_string()),
+                ..Record::default()
+            },
+        );
+        save(filename, &state).unwrap();
+        let loaded = load(filename);
+        std::fs::remove_file(filename).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert_eq!(format!("{loaded:?}"), format!("{state:?}"));
+    }
+
+    #[test]
+    fn load_without_identity() {
+        // State files written before server, zone and key were recorded
+        let state: State = toml::from_str(
+            r#"
+[records."host.example.net A"]
+addr = "192.0.2.1"
+ttl = 60
+time = 1700000000
+"#,
+        )
+        .unwrap();
+        let record = &state.records["host.example.net A"];
+        assert_eq!(record.addr, Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(record.failures, 0);
+        assert!(record.server.is_none() && record.zone.is_none() && record.key.is_none());
+    }
+}
@@ -0,0 +1,204 @@
+//! Explain which addresses the records would be updated to, and why
+
+use crate::ifaces::{self, Address, Change};
+use crate::{AddressFamily, ConfigSource, Daemon, Error, Options, RecordState};
+use std::net::IpAddr;
+
+/// Print all addresses, the address that each record and neighbor
+/// would be updated to, and what DNS currently has. With `watch`,
+/// keep printing address changes and how they affect the records.
+///
+/// Nothing is updated.
+///
+/// # Errors
+///
+/// Will return `Err` if the configuration cannot be loaded or is
+/// invalid, a key cannot be set up, or netlink cannot be queried.
+pub async fn status(config_file: &str, watch: bool) -> Result<(), Error> {
+    let source = ConfigSource::File(config_file.to_string());
+    let mut daemon = Daemon::new(source, Options { dry_run: true }).await?;
+
+    let mut changes = None;
+    let mut addresses = if watch {
+        let changes = changes.insert(ifaces::watch());
+        let mut addresses: Vec<Address> = vec![];
+        while let Some(change) = changes.recv().await {
+            match change {
+                Change::Added(address) => {
+                    addresses.retain(|a| a.addr != address.addr);
+                    addresses.push(address);
+                }
+                Change::Removed(address) => addresses.retain(|a| a.addr != address.addr),
+                Change::InitialDumpDone(_) => break,
+            }
+        }
+        addresses
+    } else {
+        ifaces::dump_all().await?
+    };
+    for address in &addresses {
+        if !address.ignored {
+            daemon.set_address(&address.interface, address.addr);
+        }
+    }
+
+    println!("Interfaces:");
+    let mut interfaces = Vec::new();
+    for address in &addresses {
+        if !interfaces.contains(&address.interface.as_str()) {
+            interfaces.push(&address.interface);
+        }
+    }
+    for interface in interfaces {
+        println!("  {interface}");
+        for address in addresses.iter().filter(|a| a.interface == interface) {
+            let ignored = if address.ignored { " (ignored)" } else { "" };
+            println!("    {address}{ignored}");
+        }
+    }
+
+    println!("Records:");
+    let mut record_states = daemon.iface_states.values().flatten().collect::<Vec<_>>();
+    record_states.sort_by_key(|record_state| (record_state.interface(), record_state.name.clone()));
+    for record_state in record_states {
+        print_record(record_state, &addresses).await;
+    }
+
+    let Some(mut changes) = changes else {
+        return Ok(());
+    };
+    println!("Watching for changes:");
+    while let Some(change) = changes.recv().await {
+        match change {
+            Change::Added(address) => {
+                println!("+ {}: {address}", address.interface);
+                if !address.ignored {
+                    daemon.set_address(&address.interface, address.addr);
+                }
+                for record_state in daemon
+                    .iface_states
+                    .get(&address.interface)
+                    .into_iter()
+                    .flatten()
+                {
+                    if is_family(record_state, address.addr) {
+                        println!(
+                            "    {}: {}",
+                            describe(record_state),
+                            annotation(record_state, &address)
+                        );
+                    }
+                }
+                // Flags such as `tentative` change without removal
+                addresses.retain(|a| a.addr != address.addr);
+                addresses.push(address);
+            }
+            Change::Removed(address) => {
+                println!("- {}: {address}", address.interface);
+                if let Some(addrs) = daemon.iface_addrs.get_mut(&address.interface) {
+                    addrs.retain(|a| *a != address.addr);
+                }
+                for record_state in daemon
+                    .iface_states
+                    .get(&address.interface)
+                    .into_iter()
+                    .flatten()
+                {
+                    if record_state.addr == Some(address.addr) {
+                        println!(
+                            "    {}: keeps {} until another address appears",
+                            describe(record_state),
+                            address.addr
+                        );
+                    }
+                }
+                addresses.retain(|a| a.addr != address.addr);
+            }
+            Change::InitialDumpDone(_) => {}
+        }
+    }
+    Err(Error::NetlinkStopped)
+}
+
+/// Like `name A on eth0`
+fn describe(record_state: &RecordState) -> String {
+    format!(
+        "{} {} on {}",
+        record_state
+            .name
+            .as_deref()
+            .map_or("(neighbors)", String::as_str),
+        record_state.record_type,
+        record_state.interface()
+    )
+}
+
+fn is_family(record_state: &RecordState, addr: IpAddr) -> bool {
+    match record_state.af {
+        AddressFamily::IPv4 => addr.is_ipv4(),
+        AddressFamily::IPv6 => addr.is_ipv6(),
+    }
+}
+
+/// Why `address` has or has not been selected for `record_state`
+fn annotation(record_state: &RecordState, address: &Address) -> String {
+    if address.ignored {
+        if address.flags.contains(&"temporary") {
+            "ignored, temporary".to_string()
+        } else {
+            "ignored, secondary".to_string()
+        }
+    } else if !record_state.scope.contains(&address.addr) {
+        format!("outside of scope {}", record_state.scope)
+    } else if record_state.addr == Some(address.addr) {
+        "selected".to_string()
+    } else {
+        "in scope, but another address has been seen later".to_string()
+    }
+}
+
+async fn print_record(record_state: &RecordState, addresses: &[Address]) {
+    println!(
+        "  {} (key {}, zone {}, ttl {}, scope {})",
+        describe(record_state),
+        record_state.task.key,
+        record_state
+            .zone
+            .as_deref()
+            .map_or("(from name)", String::as_str),
+        record_state.ttl,
+        record_state.scope
+    );
+    for address in addresses
+        .iter()
+        .filter(|a| a.interface == record_state.interface() && is_family(record_state, a.addr))
+    {
+        println!(
+            "    {}/{}: {}",
+            address.addr,
+            address.prefix_len,
+            annotation(record_state, address)
+        );
+    }
+    let Some(addr) = record_state.addr else {
+        println!("    no address to publish");
+        return;
+    };
+    for (name, addr) in record_state.targets(addr) {
+        let mut server = record_state.server.lock().await;
+        let answer = match server.query(&name, record_state.record_type).await {
+            Ok(addrs) if addrs == [(addr, record_state.ttl)] => "in sync".to_string(),
+            Ok(addrs) if addrs.is_empty() => "DNS has no record".to_string(),
+            Ok(addrs) => format!(
+                "DNS has {}",
+                addrs
+                    .iter()
+                    .map(|(addr, ttl)| format!("{addr} ttl {ttl}"))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Err(e) => format!("cannot query DNS: {e}"),
+        };
+        println!("    {name} -> {addr}: {answer}");
+    }
+}
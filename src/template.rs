@@ -0,0 +1,139 @@
+//! Placeholders in names and zones, so that one configuration can be
+//! deployed to many hosts:
+//!
+//! - `{hostname}`: the host name up to the first dot
+//! - `{interface}`: the interface of the record, which can differ
+//!   per address family in a `[[record]]`
+//! - `{machine-id-short}`: the first 8 characters of `/etc/machine-id`
+//! - `{env:VAR}`: the environment variable `VAR`
+
+use std::fs;
+use std::io;
+
+const HOSTNAME_FILE: &str = "/proc/sys/kernel/hostname";
+const MACHINE_ID_FILE: &str = "/etc/machine-id";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Unterminated placeholder in {0:?}")]
+    Unterminated(String),
+    #[error("Unknown placeholder {{{0}}}")]
+    Unknown(String),
+    #[error("Environment variable {0} is not set")]
+    EnvNotSet(String),
+    #[error("Cannot read {path}: {source}")]
+    Read {
+        path: &'static str,
+        #[source]
+        source: io::Error,
+    },
+}
+
+impl Error {
+    /// Whether the value is only known on the host that runs the
+    /// service, like environment variables and the machine id
+    #[must_use]
+    pub fn depends_on_host(&self) -> bool {
+        matches!(self, Error::EnvNotSet(_) | Error::Read { .. })
+    }
+}
+
+/// Replace all placeholders in `template`. Without `interface`,
+/// `{interface}` is kept for [`expand_interface`].
+///
+/// # Errors
+///
+/// Will return `Err` if a placeholder is unknown or unterminated, or
+/// if its value cannot be determined.
+pub fn expand(template: &str, interface: Option<&str>) -> Result<String, Error> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let Some(end) = rest[start..].find('}') else {
+            return Err(Error::Unterminated(template.to_string()));
+        };
+        let placeholder = &rest[start + 1..start + end];
+        expanded.push_str(&value(placeholder, interface)?);
+        rest = &rest[start + end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Replace the `{interface}` placeholders that [`expand`] has kept
+#[must_use]
+pub fn expand_interface(template: &str, interface: &str) -> String {
+    template.replace("{interface}", interface)
+}
+
+fn value(placeholder: &str, interface: Option<&str>) -> Result<String, Error> {
+    if let Some(variable) = placeholder.strip_prefix("env:") {
+        return std::env::var(variable).map_err(|_| Error::EnvNotSet(variable.to_string()));
+    }
+    match placeholder {
+        "hostname" => {
+            let hostname = read(HOSTNAME_FILE)?;
+            Ok(hostname.split('.').next().unwrap_or_default().to_string())
+        }
+        "interface" => Ok(interface.unwrap_or("{interface}").to_string()),
+        "machine-id-short" => Ok(read(MACHINE_ID_FILE)?.chars().take(8).collect()),
+        _ => Err(Error::Unknown(placeholder.to_string())),
+    }
+}
+
+fn read(path: &'static str) -> Result<String, Error> {
+    fs::read_to_string(path)
+        .map(|s| s.trim().to_string())
+        .map_err(|source| Error::Read { path, source })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn without_placeholders() {
+        assert_eq!(
+            expand("host.example.net", Some("eth0")).unwrap(),
+            "host.example.net"
+        );
+    }
+
+    #[test]
+    fn interface() {
+        assert_eq!(
+            expand("{interface}.{interface}.example.net", Some("eth0")).unwrap(),
+            "eth0.eth0.example.net"
+        );
+    }
+
+    #[test]
+    fn interface_per_family() {
+        let kept = expand("{interface}.example.net", None).unwrap();
+        assert_eq!(kept, "{interface}.example.net");
+        assert_eq!(expand_interface(&kept, "ppp0"), "ppp0.example.net");
+    }
+
+    #[test]
+    fn env() {
+        let path = std::env::var("PATH").unwrap();
+        assert_eq!(expand("{env:PATH}", None).unwrap(), path);
+        let e = expand("{env:IFDYNDNSD_TEST_UNSET}.example.net", None).unwrap_err();
+        assert!(matches!(&e, Error::EnvNotSet(variable) if variable == "IFDYNDNSD_TEST_UNSET"));
+        assert!(e.depends_on_host());
+    }
+
+    #[test]
+    fn unknown() {
+        let e = expand("{bogus}.example.net", None).unwrap_err();
+        assert!(matches!(&e, Error::Unknown(placeholder) if placeholder == "bogus"));
+        assert!(!e.depends_on_host());
+    }
+
+    #[test]
+    fn unterminated() {
+        let e = expand("host-{hostname.example.net", None).unwrap_err();
+        assert!(matches!(e, Error::Unterminated(_)));
+    }
+}